@@ -0,0 +1,137 @@
+// Uploads files in the background with a progress bar and a cancel
+// button. Without JavaScript the form is submitted as usual.
+(function () {
+  'use strict';
+
+  var form = document.getElementById('file-form');
+  var input = document.getElementById('file');
+  if (!form || !input || !window.XMLHttpRequest || !window.FormData) {
+    return;
+  }
+
+  var dropZone = document.createElement('div');
+  dropZone.className = 'drop-zone';
+  dropZone.textContent = 'Or drop files here';
+  form.insertBefore(dropZone, form.firstChild);
+
+  var status = document.createElement('div');
+  status.className = 'upload-status';
+  status.hidden = true;
+  status.innerHTML =
+    '<progress max="100" value="0"></progress> ' +
+    '<span class="upload-percent"></span> ' +
+    '<button type="button" class="upload-cancel">Cancel</button>';
+  form.appendChild(status);
+
+  var progressBar = status.querySelector('progress');
+  var percent = status.querySelector('.upload-percent');
+  var cancel = status.querySelector('.upload-cancel');
+
+  var results = document.createElement('ul');
+  results.className = 'upload-results';
+  form.appendChild(results);
+
+  var xhr = null;
+
+  function uploadId() {
+    if (window.crypto && crypto.randomUUID) {
+      return crypto.randomUUID();
+    }
+    return Date.now().toString(36) + '-' +
+      Math.random().toString(36).slice(2);
+  }
+
+  function showResult(text, failed) {
+    var item = document.createElement('li');
+    item.textContent = text;
+    if (failed) {
+      item.className = 'upload-failed';
+    }
+    results.appendChild(item);
+  }
+
+  function showResponse(response) {
+    var files = (response && response.files) || [];
+    if (files.length === 0) {
+      showResult((response && response.message) || 'Upload failed', true);
+    }
+    files.forEach(function (file) {
+      if (file.status === 200) {
+        showResult(file.name + ': ' + file.size + ' bytes' +
+                   (file.duplicate ? ' (uploaded before)' : ''), false);
+      } else {
+        showResult(file.name + ': ' + file.message, true);
+      }
+    });
+  }
+
+  function upload(files) {
+    if (xhr || files.length === 0) {
+      return;
+    }
+
+    var data = new FormData();
+    for (var i = 0; i < files.length; i++) {
+      data.append('file', files[i], files[i].name);
+    }
+
+    xhr = new XMLHttpRequest();
+    xhr.open('POST', '/api/v1/file');
+    xhr.setRequestHeader('X-Upload-Id', uploadId());
+    xhr.responseType = 'json';
+
+    xhr.upload.onprogress = function (e) {
+      if (e.lengthComputable) {
+        var done = Math.floor(e.loaded * 100 / e.total);
+        progressBar.value = done;
+        percent.textContent = done + '%';
+      }
+    };
+    xhr.onload = function () {
+      showResponse(xhr.response);
+    };
+    xhr.onerror = function () {
+      showResult('Connection failed', true);
+    };
+    xhr.onabort = function () {
+      showResult('Cancelled', true);
+    };
+    xhr.onloadend = function () {
+      xhr = null;
+      status.hidden = true;
+      form.reset();
+    };
+
+    progressBar.value = 0;
+    percent.textContent = '0%';
+    status.hidden = false;
+    xhr.send(data);
+  }
+
+  form.addEventListener('submit', function (e) {
+    e.preventDefault();
+    upload(input.files);
+  });
+
+  cancel.addEventListener('click', function () {
+    if (xhr) {
+      xhr.abort();
+    }
+  });
+
+  ['dragenter', 'dragover'].forEach(function (name) {
+    dropZone.addEventListener(name, function (e) {
+      e.preventDefault();
+      dropZone.classList.add('drop-zone-active');
+    });
+  });
+  ['dragleave', 'drop'].forEach(function (name) {
+    dropZone.addEventListener(name, function (e) {
+      e.preventDefault();
+      dropZone.classList.remove('drop-zone-active');
+    });
+  });
+  dropZone.addEventListener('drop', function (e) {
+    upload(e.dataTransfer.files);
+  });
+})();
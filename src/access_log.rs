@@ -0,0 +1,296 @@
+//! Access log in Common or Combined Log Format or as JSON lines,
+//! see `--access-log`
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Written instead of a value that's unknown in the text formats
+const MISSING: &str = "-";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Common Log Format
+    Common,
+
+    /// Combined Log Format, Common plus referer and user agent
+    Combined,
+
+    /// A JSON object per line with everything we know
+    Json,
+}
+
+/// One handled request
+pub struct Entry<'a> {
+    pub remote_addr: SocketAddr,
+    pub user: Option<&'a str>,
+    pub time: chrono::DateTime<chrono::Local>,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub http_version: &'a str,
+    pub status: Option<u16>,
+
+    /// Request body bytes we've read
+    pub bytes_received: u64,
+
+    /// Response size including the headers
+    pub bytes_sent: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+
+    /// Names of the payloads stored while handling the request
+    pub stored_filenames: &'a [String],
+    pub duration: std::time::Duration,
+}
+
+/// Escapes the string for the text formats so it can't break the line
+/// into wrong fields
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() || c.is_whitespace() && c != ' ' =>
+                out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+impl Entry<'_> {
+    pub fn format(&self, format: LogFormat) -> String {
+        if format == LogFormat::Json {
+            return serde_json::json!({
+                "remote_addr": self.remote_addr.ip().to_string(),
+                "user": self.user,
+                "time": self.time.to_rfc3339(),
+                "method": self.method,
+                "url": self.url,
+                "http_version": self.http_version,
+                "status": self.status,
+                "bytes_received": self.bytes_received,
+                "bytes_sent": self.bytes_sent,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "stored_filenames": self.stored_filenames,
+                "duration_us": self.duration.as_micros() as u64,
+            }).to_string();
+        }
+
+        // the user is the only field that isn't quoted
+        let user = self.user.map(|user| escape(user).replace(' ', "\\x20"));
+        let mut line = format!(
+            "{} - {} [{}] {} {} {}",
+            self.remote_addr.ip(),
+            user.as_deref().unwrap_or(MISSING),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            quote(&format!("{} {} HTTP/{}",
+                           self.method, self.url, self.http_version)),
+            self.status.map_or(MISSING.to_string(), |s| s.to_string()),
+            self.bytes_sent);
+
+        if format == LogFormat::Combined {
+            line.push_str(&format!(
+                " {} {}",
+                quote(self.referer.unwrap_or(MISSING)),
+                quote(self.user_agent.unwrap_or(MISSING))));
+        }
+        line
+    }
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+
+    /// None for stdout
+    path: Option<PathBuf>,
+    out: Mutex<Box<dyn Write + Send>>,
+
+    /// Set on SIGHUP, the file is reopened before the next write
+    reopen: Arc<AtomicBool>,
+}
+
+fn open(path: &Option<PathBuf>) -> io::Result<Box<dyn Write + Send>> {
+    Ok(match path {
+        Some(path) => Box::new(fs::OpenOptions::new()
+                               .create(true)
+                               .append(true)
+                               .open(path)?),
+        None => Box::new(io::stdout()),
+    })
+}
+
+impl AccessLog {
+    /// Appends to the file at `path`, "-" means stdout
+    pub fn open(path: &str, format: LogFormat) -> io::Result<Self> {
+        let path = if path == "-" { None } else { Some(PathBuf::from(path)) };
+        Ok(AccessLog {
+            format,
+            out: Mutex::new(open(&path)?),
+            path,
+            reopen: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Setting it makes the log reopen the file, e.g. after it's rotated
+    pub fn reopen_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.reopen)
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let mut out = self.out.lock().unwrap();
+
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            match open(&self.path) {
+                Ok(file) => *out = file,
+                Err(e) => println!("Could not reopen the access log: {}", e),
+            }
+        }
+
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            println!("Could not write the access log: {}", e);
+        }
+    }
+}
+
+/// Passes the response through, counting bytes and picking up
+/// the status code from the status line
+pub struct ResponseWriter<W> {
+    inner: W,
+    bytes: u64,
+
+    /// Beginning of the status line, "HTTP/1.1 200"
+    status_line: Vec<u8>,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ResponseWriter { inner, bytes: 0, status_line: Vec::new() }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        let line = std::str::from_utf8(&self.status_line).ok()?;
+        line.split(' ').nth(1)?.parse().ok()
+    }
+}
+
+impl<W: Write> Write for ResponseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        const STATUS_LINE_LEN: usize = "HTTP/1.1 200".len();
+        if self.status_line.len() < STATUS_LINE_LEN {
+            let wanted = STATUS_LINE_LEN - self.status_line.len();
+            self.status_line.extend_from_slice(&buf[..written.min(wanted)]);
+        }
+
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use chrono::TimeZone;
+    use crate::test_util::test_dir;
+
+    fn entry(stored_filenames: &[String]) -> Entry<'_> {
+        Entry {
+            remote_addr: "192.0.2.1:54321".parse().unwrap(),
+            user: Some("alice"),
+            time: chrono::Local.ymd(2021, 3, 14).and_hms(15, 9, 26),
+            method: "POST",
+            url: "/file",
+            http_version: "1.1",
+            status: Some(200),
+            bytes_received: 1234,
+            bytes_sent: 512,
+            referer: None,
+            user_agent: Some("curl/7.68.0 \"quoted\""),
+            stored_filenames,
+            duration: std::time::Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let entry = entry(&[]);
+        let time = entry.time.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+
+        assert_eq!(
+            entry.format(LogFormat::Common),
+            format!(r#"192.0.2.1 - alice [{}] "POST /file HTTP/1.1" 200 512"#,
+                    time));
+        assert_eq!(
+            entry.format(LogFormat::Combined),
+            format!(r#"192.0.2.1 - alice [{}] "POST /file HTTP/1.1" 200 512 "-" "curl/7.68.0 \"quoted\"""#,
+                    time));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let stored = vec!["cat.jpg--payload".to_string()];
+        let json: serde_json::Value = serde_json::from_str(
+            &entry(&stored).format(LogFormat::Json)).unwrap();
+
+        assert_eq!(json["remote_addr"], "192.0.2.1");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes_received"], 1234);
+        assert_eq!(json["bytes_sent"], 512);
+        assert_eq!(json["user_agent"], "curl/7.68.0 \"quoted\"");
+        assert_eq!(json["stored_filenames"][0], "cat.jpg--payload");
+        assert_eq!(json["referer"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_response_writer() {
+        let mut writer = ResponseWriter::new(Vec::new());
+        writer.write_all(b"HTTP/1.1 4").unwrap();
+        writer.write_all(b"04 Not Found\r\n\r\n").unwrap();
+
+        assert_eq!(writer.status(), Some(404));
+        assert_eq!(writer.bytes(), 26);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = test_dir("access-log");
+        let path = dir.join("access.log");
+
+        let log = AccessLog::open(path.to_str().unwrap(), LogFormat::Json)
+            .unwrap();
+        log.log(&entry(&[]));
+
+        // what logrotate does
+        let rotated = dir.join("access.log.1");
+        fs::rename(&path, &rotated).unwrap();
+        log.reopen_flag().store(true, Ordering::Relaxed);
+        log.log(&entry(&[]));
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fs;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+
+use crate::error::WhateverError;
+
+/// Users and tokens that are allowed to use the server
+pub struct Auth {
+    /// user name => bcrypt or argon2 password hash
+    users: HashMap<String, String>,
+
+    /// static bearer tokens
+    tokens: Vec<String>,
+}
+
+/// Compares two byte strings in time that only depends on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Lines that aren't empty or comments
+fn meaningful_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// User name and password from Basic auth credentials
+fn decode_basic(credentials: &str) -> Option<(String, String)> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials)
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// User name from the Authorization header if it's Basic auth,
+/// whether the password is right or not
+pub fn basic_user(authorization: &str) -> Option<String> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    decode_basic(credentials.trim()).map(|(user, _)| user)
+}
+
+fn is_bcrypt_hash(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if is_bcrypt_hash(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl Auth {
+    /// Loads an htpasswd-style file with `user:hash` lines
+    /// and/or a file with one bearer token per line
+    pub fn load(htpasswd_path: Option<&str>, tokens_path: Option<&str>)
+                -> Result<Self, WhateverError> {
+        let mut auth = Auth { users: HashMap::new(), tokens: Vec::new() };
+
+        if let Some(path) = htpasswd_path {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            auth.parse_htpasswd(&content)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+
+        if let Some(path) = tokens_path {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            auth.tokens.extend(
+                meaningful_lines(&content).map(|(_, token)| token.to_string()));
+        }
+
+        if auth.users.is_empty() && auth.tokens.is_empty() {
+            return Err("Authentication is enabled but no users or tokens \
+                        are configured".into());
+        }
+
+        Ok(auth)
+    }
+
+    fn parse_htpasswd(&mut self, content: &str) -> Result<(), WhateverError> {
+        for (line_num, line) in meaningful_lines(content) {
+            let (user, hash) = line.split_once(':')
+                .ok_or_else(|| format!(
+                    "line {}: expected \"user:hash\"", line_num))?;
+
+            if !is_bcrypt_hash(hash) && !hash.starts_with("$argon2") {
+                return Err(format!(
+                    "line {}: unsupported hash for user \"{}\", \
+                     only bcrypt and argon2 are supported", line_num, user)
+                           .into());
+            }
+            if !is_bcrypt_hash(hash) && PasswordHash::new(hash).is_err() {
+                return Err(format!(
+                    "line {}: invalid argon2 hash for user \"{}\"",
+                    line_num, user).into());
+            }
+
+            self.users.insert(user.to_string(), hash.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Whether we accept basic auth, for the WWW-Authenticate header
+    pub fn accepts_basic(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Whether we accept bearer tokens, for the WWW-Authenticate header
+    pub fn accepts_bearer(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Checks the value of the Authorization header
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let authorization = match authorization {
+            Some(value) => value.trim(),
+            None => return false,
+        };
+        let (scheme, credentials) = match authorization.split_once(' ') {
+            Some((scheme, credentials)) => (scheme, credentials.trim()),
+            None => return false,
+        };
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            self.check_basic(credentials)
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            self.check_bearer(credentials)
+        } else {
+            false
+        }
+    }
+
+    fn check_basic(&self, credentials: &str) -> bool {
+        let (user, password) = match decode_basic(credentials) {
+            Some(pair) => pair,
+            None => return false,
+        };
+
+        match self.users.get(&user) {
+            Some(hash) => verify_password(&password, hash),
+            None => false,
+        }
+    }
+
+    fn check_bearer(&self, token: &str) -> bool {
+        // check every token so the timing doesn't tell which one matched
+        self.tokens.iter()
+            .fold(false, |found, t| {
+                constant_time_eq(t.as_bytes(), token.as_bytes()) | found
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use argon2::PasswordHasher;
+    use argon2::password_hash::SaltString;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", user, password)))
+    }
+
+    fn auth_with_users() -> Auth {
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        let mut auth = Auth { users: HashMap::new(), tokens: Vec::new() };
+        auth.parse_htpasswd(&format!(
+            "# comment\nalice:{}\n\nbob:{}\n", bcrypt_hash, argon2_hash))
+            .unwrap();
+        auth
+    }
+
+    #[test]
+    fn test_basic_bcrypt() {
+        let auth = auth_with_users();
+        assert!(auth.is_authorized(Some(&basic("alice", "hunter2"))));
+        assert!(!auth.is_authorized(Some(&basic("alice", "hunter3"))));
+    }
+
+    #[test]
+    fn test_basic_argon2() {
+        let auth = auth_with_users();
+        assert!(auth.is_authorized(Some(&basic("bob", "correct horse"))));
+        assert!(!auth.is_authorized(Some(&basic("bob", "hunter2"))));
+        assert!(!auth.is_authorized(Some(&basic("carol", "correct horse"))));
+    }
+
+    #[test]
+    fn test_basic_user() {
+        assert_eq!(basic_user(&basic("alice", "wrong")).as_deref(),
+                   Some("alice"));
+        assert_eq!(basic_user("Bearer alice"), None);
+        assert_eq!(basic_user("Basic !!!"), None);
+    }
+
+    #[test]
+    fn test_bearer() {
+        let auth = Auth {
+            users: HashMap::new(),
+            tokens: vec!["first".to_string(), "second".to_string()],
+        };
+        assert!(auth.is_authorized(Some("Bearer second")));
+        assert!(auth.is_authorized(Some("bearer first")));
+        assert!(!auth.is_authorized(Some("Bearer third")));
+        assert!(!auth.is_authorized(Some("Basic second")));
+        assert!(!auth.is_authorized(None));
+    }
+
+    #[test]
+    fn test_unsupported_hash() {
+        let mut auth = Auth { users: HashMap::new(), tokens: Vec::new() };
+        assert!(auth.parse_htpasswd("alice:$apr1$abc$def").is_err());
+        assert!(auth.parse_htpasswd("alice").is_err());
+    }
+}
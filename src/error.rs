@@ -28,17 +28,29 @@ pub enum ErrorKind {
     Success,
     ServerError,
     UserError,
+    Unauthorized,
     NotFound,
+    Conflict,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    Unavailable,
     Unknown,
 }
 
 impl ErrorKind {
-    fn as_http_code(self) -> u16 {
+    pub fn as_http_code(self) -> u16 {
         match self {
             ErrorKind::Success => 200,
             ErrorKind::ServerError => 500,
             ErrorKind::UserError => 400,
+            ErrorKind::Unauthorized => 401,
             ErrorKind::NotFound => 404,
+            ErrorKind::Conflict => 409,
+            ErrorKind::PreconditionFailed => 412,
+            ErrorKind::PayloadTooLarge => 413,
+            ErrorKind::UnsupportedMediaType => 415,
+            ErrorKind::Unavailable => 503,
             ErrorKind::Unknown => 500,
         }
     }
@@ -48,7 +60,13 @@ impl ErrorKind {
             ErrorKind::Success => "Success",
             ErrorKind::ServerError => "Server error",
             ErrorKind::UserError => "Client error",
+            ErrorKind::Unauthorized => "Unauthorized",
             ErrorKind::NotFound => "Not found",
+            ErrorKind::Conflict => "Conflict",
+            ErrorKind::PreconditionFailed => "Precondition failed",
+            ErrorKind::PayloadTooLarge => "Payload too large",
+            ErrorKind::UnsupportedMediaType => "Unsupported media type",
+            ErrorKind::Unavailable => "Service unavailable",
             ErrorKind::Unknown => "Unknown",
         }
     }
@@ -86,6 +104,14 @@ impl Error {
         self.kind.as_http_code()
     }
 
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "status": self.as_http_code(),
+            "error": self.kind.description(),
+            "message": self.msg,
+        })
+    }
+
     pub fn as_html(&self) -> String {
         format!("{} ({}): {}",
                 self.kind.as_http_code(), self.kind.description(), self.msg)
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use openssl::sha::Sha256;
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Size and hex SHA-256 of the file
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<(u64, String)> {
+    let mut file = fs::File::open(path)?;
+    let mut writer = Sha256Writer::new(io::sink());
+    io::copy(&mut file, &mut writer)?;
+    Ok(writer.finish())
+}
+
+/// Passes everything through to the inner writer while computing
+/// SHA-256 and the size of what's been written
+pub struct Sha256Writer<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Sha256Writer { inner, hasher: Sha256::new(), size: 0 }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Size and hex SHA-256 of what's been written
+    pub fn finish(self) -> (u64, String) {
+        (self.size, to_hex(&self.hasher.finish()))
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use crate::test_util::test_dir;
+
+    const HELLO_SHA256: &str =
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_sha256_file() {
+        let dir = test_dir("sha256");
+        let path = dir.join("hello");
+        fs::write(&path, b"hello").unwrap();
+
+        assert_eq!(sha256_file(&path).unwrap(), (5, HELLO_SHA256.to_string()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut out = Vec::new();
+        let mut writer = Sha256Writer::new(&mut out);
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo").unwrap();
+
+        assert_eq!(writer.finish(), (5, HELLO_SHA256.to_string()));
+        assert_eq!(out, b"hello");
+    }
+}
@@ -0,0 +1,213 @@
+//! `--on-upload` hook: a shell command that's run after every stored
+//! payload. It gets the details in environment variables:
+//!
+//! - `UPLOAD_PATH` where the payload is stored
+//! - `UPLOAD_ORIGINAL_NAME` the name the client has sent, if any
+//! - `UPLOAD_TYPE` "text" or "file"
+//! - `UPLOAD_SIZE` in bytes
+//! - `UPLOAD_SHA256`
+//! - `UPLOAD_METADATA_PATH` if metadata is saved
+//!
+//! Events that don't fit in the queue while the threads are busy are
+//! dropped.
+
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often we check whether the command has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many events may wait for a free thread
+const QUEUE_SIZE: usize = 1000;
+
+/// A payload that's been stored
+pub struct UploadEvent {
+    pub path: PathBuf,
+    pub original_name: Option<String>,
+    pub typ: &'static str,
+    pub size: u64,
+    pub sha256: String,
+    pub metadata_path: Option<PathBuf>,
+}
+
+/// Runs the command for every event on a few background threads,
+/// so uploads don't wait for it
+pub struct Hooks {
+    sender: mpsc::SyncSender<UploadEvent>,
+}
+
+impl Hooks {
+    /// Starts `concurrency` threads that run `command` with `sh -c`,
+    /// each run is killed after `timeout`
+    pub fn start(command: &str, timeout: Duration, concurrency: usize) -> Self {
+        Self::with_queue_size(command, timeout, concurrency, QUEUE_SIZE)
+    }
+
+    fn with_queue_size(command: &str, timeout: Duration, concurrency: usize,
+                       queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<UploadEvent>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..concurrency {
+            let receiver = Arc::clone(&receiver);
+            let command = command.to_string();
+            thread::spawn(move || loop {
+                let event = match receiver.lock().unwrap().recv() {
+                    Ok(event) => event,
+                    Err(mpsc::RecvError) => return,
+                };
+
+                if let Err(e) = run_hook(&command, timeout, &event) {
+                    println!("on-upload hook for {} failed: {}",
+                             event.path.display(), e);
+                }
+            });
+        }
+
+        Hooks { sender }
+    }
+
+    /// Queues the hook for the event, failures are only logged
+    pub fn notify(&self, event: UploadEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                println!("on-upload hook queue is full, dropping {}",
+                         event.path.display());
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                println!("on-upload hook threads have quit");
+            }
+        }
+    }
+}
+
+/// Runs the command and waits for it up to `timeout`,
+/// Err describes what went wrong
+fn run_hook(command: &str, timeout: Duration, event: &UploadEvent)
+            -> Result<(), String> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        // in a group of its own, so whatever sh starts can be killed too
+        .process_group(0)
+        .env("UPLOAD_PATH", &event.path)
+        .env("UPLOAD_TYPE", event.typ)
+        .env("UPLOAD_SIZE", event.size.to_string())
+        .env("UPLOAD_SHA256", &event.sha256);
+    if let Some(original_name) = &event.original_name {
+        cmd.env("UPLOAD_ORIGINAL_NAME", original_name);
+    }
+    if let Some(metadata_path) = &event.metadata_path {
+        cmd.env("UPLOAD_METADATA_PATH", metadata_path);
+    }
+
+    let mut child = cmd.spawn()
+        .map_err(|e| format!("cannot run \"{}\": {}", command, e))?;
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("exited with {}", status)),
+            Ok(None) if started.elapsed() >= timeout => {
+                // the process may exit right before we kill it,
+                // wait() reaps it either way. The group id is the pid
+                // of its first process
+                unsafe {
+                    libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Err(format!("killed after {} s", timeout.as_secs_f32()));
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => return Err(format!("cannot wait for it: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use std::fs;
+    use crate::test_util::test_dir;
+
+    fn event(dir: &std::path::Path) -> UploadEvent {
+        UploadEvent {
+            path: dir.join("cat.jpg--payload"),
+            original_name: Some("cat.jpg".to_string()),
+            typ: "file",
+            size: 4,
+            sha256: "abc".to_string(),
+            metadata_path: None,
+        }
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let dir = test_dir("hook");
+        let out = dir.join("out");
+
+        let command = format!(
+            "echo \"$UPLOAD_ORIGINAL_NAME $UPLOAD_TYPE $UPLOAD_SIZE \
+             ${{UPLOAD_METADATA_PATH-none}}\" > {}",
+            out.display());
+        run_hook(&command, Duration::from_secs(5), &event(&dir)).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "cat.jpg file 4 none\n");
+
+        assert!(run_hook("exit 3", Duration::from_secs(5), &event(&dir))
+                .unwrap_err()
+                .contains("3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let dir = test_dir("hook-queue");
+        let out = dir.join("out");
+
+        // one event runs and one waits, the rest don't fit
+        let command = format!("sleep 0.3; echo run >> {}", out.display());
+        let hooks = Hooks::with_queue_size(&command, Duration::from_secs(5),
+                                           1, 1);
+        for _ in 0..5 {
+            hooks.notify(event(&dir));
+        }
+
+        thread::sleep(Duration::from_millis(1500));
+        let runs = fs::read_to_string(&out).unwrap().lines().count();
+        assert!((1..=2).contains(&runs));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hook_timeout() {
+        let dir = test_dir("hook-timeout");
+        let pid_path = dir.join("pid");
+
+        let started = Instant::now();
+        let command = format!("sleep 10 & echo $! > {}; wait",
+                              pid_path.display());
+        let result = run_hook(&command, Duration::from_millis(300),
+                              &event(&dir));
+        assert!(result.unwrap_err().contains("killed"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // sleep is killed along with sh. It may linger as a zombie
+        // until it's reaped by init
+        let pid = fs::read_to_string(&pid_path).unwrap();
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        assert!(stat.map_or(true, |stat| stat.contains(") Z ")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
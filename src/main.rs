@@ -1,4 +1,4 @@
-#![feature(test)]
+#![cfg_attr(test, feature(test))]
 
 extern crate chrono;
 extern crate form_urlencoded;
@@ -6,11 +6,26 @@ extern crate form_urlencoded;
 #[macro_use]
 extern crate rust_embed;
 
+mod access_log;
+mod auth;
 mod error;
+mod hash;
+mod hooks;
+mod metrics;
+mod mime;
+mod name_template;
+mod progress;
+mod quota;
 mod sanitize_filename;
 mod srv;
+mod transliterate;
+mod tus;
+mod webhook;
 mod config;
 
+#[cfg(test)]
+mod test_util;
+
 use std::env;
 use config::Config;
 
@@ -25,9 +40,14 @@ fn main() {
             return;
         }
     };
-    let mut srv = config.make_server();
+    let srv = config.make_server();
+    if let Err(e) = srv.handle_signals() {
+        eprintln!("Cannot handle signals: {}", e);
+        return;
+    }
 
-    println!("Listening at {}, upload directiory: {}, name is {}",
-             config.listen_addr, config.uploads_dir, config.send_to_name);
+    println!("Listening at {}, upload directiory: {}, name is {}, {} workers",
+             srv.base_url(), config.uploads_dir, config.send_to_name,
+             config.workers);
     srv.run();
 }
@@ -0,0 +1,166 @@
+//! Counters exposed at `/metrics` in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the upload duration buckets in seconds
+const DURATION_BUCKETS: &[f64] =
+    &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&le| value <= le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Handled requests by route and response status
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+
+    /// Stored payload bytes by upload type
+    uploaded_bytes: Mutex<BTreeMap<&'static str, u64>>,
+
+    /// How long upload requests took by route
+    upload_durations: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    uploads_in_flight: AtomicUsize,
+}
+
+/// Counts the upload as in flight while it's alive
+pub struct InFlightGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.uploads_in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn request_handled(&self, route: &'static str, status: u16) {
+        *self.requests.lock().unwrap().entry((route, status)).or_default() += 1;
+    }
+
+    pub fn payload_stored(&self, typ: &'static str, size: u64) {
+        *self.uploaded_bytes.lock().unwrap().entry(typ).or_default() += size;
+    }
+
+    pub fn upload_finished(&self, route: &'static str, duration: Duration) {
+        self.upload_durations.lock().unwrap()
+            .entry(route)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn upload_started(&self) -> InFlightGuard<'_> {
+        self.uploads_in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { metrics: self }
+    }
+
+    /// `free_space` is left out if it's unknown
+    pub fn render(&self, free_space: Option<u64>) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "requests_total", "counter",
+               "Handled requests by route and status");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "upload_server_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                     route, status, count).unwrap();
+        }
+
+        header(&mut out, "uploaded_bytes_total", "counter",
+               "Bytes of stored payloads by upload type");
+        for (typ, bytes) in self.uploaded_bytes.lock().unwrap().iter() {
+            writeln!(out, "upload_server_uploaded_bytes_total{{type=\"{}\"}} {}",
+                     typ, bytes).unwrap();
+        }
+
+        header(&mut out, "upload_duration_seconds", "histogram",
+               "How long upload requests took by route");
+        for (route, histogram) in self.upload_durations.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                writeln!(out, "upload_server_upload_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                         route, le, cumulative).unwrap();
+            }
+            writeln!(out, "upload_server_upload_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                     route, histogram.count).unwrap();
+            writeln!(out, "upload_server_upload_duration_seconds_sum{{route=\"{}\"}} {}",
+                     route, histogram.sum).unwrap();
+            writeln!(out, "upload_server_upload_duration_seconds_count{{route=\"{}\"}} {}",
+                     route, histogram.count).unwrap();
+        }
+
+        header(&mut out, "uploads_in_flight", "gauge",
+               "Uploads being received right now");
+        writeln!(out, "upload_server_uploads_in_flight {}",
+                 self.uploads_in_flight.load(Ordering::Relaxed)).unwrap();
+
+        if let Some(free_space) = free_space {
+            header(&mut out, "free_bytes", "gauge",
+                   "Space left on the file system of the uploads directory");
+            writeln!(out, "upload_server_free_bytes {}", free_space).unwrap();
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, typ: &str, help: &str) {
+    writeln!(out, "# HELP upload_server_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE upload_server_{} {}", name, typ).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.request_handled("file", 200);
+        metrics.request_handled("file", 200);
+        metrics.request_handled("text", 413);
+        metrics.payload_stored("file", 1234);
+        metrics.upload_finished("file", Duration::from_millis(200));
+        metrics.upload_finished("file", Duration::from_secs(2));
+        let in_flight = metrics.upload_started();
+
+        let text = metrics.render(Some(4096));
+        let has = |line: &str| text.lines().any(|l| l == line);
+
+        assert!(has(r#"upload_server_requests_total{route="file",status="200"} 2"#));
+        assert!(has(r#"upload_server_requests_total{route="text",status="413"} 1"#));
+        assert!(has(r#"upload_server_uploaded_bytes_total{type="file"} 1234"#));
+        assert!(has(r#"upload_server_upload_duration_seconds_bucket{route="file",le="0.1"} 0"#));
+        assert!(has(r#"upload_server_upload_duration_seconds_bucket{route="file",le="0.5"} 1"#));
+        assert!(has(r#"upload_server_upload_duration_seconds_bucket{route="file",le="5"} 2"#));
+        assert!(has(r#"upload_server_upload_duration_seconds_bucket{route="file",le="+Inf"} 2"#));
+        assert!(has(r#"upload_server_upload_duration_seconds_count{route="file"} 2"#));
+        assert!(has("upload_server_uploads_in_flight 1"));
+        assert!(has("upload_server_free_bytes 4096"));
+
+        drop(in_flight);
+        assert!(metrics.render(None).contains("upload_server_uploads_in_flight 0"));
+        assert!(!metrics.render(None).contains("free_bytes"));
+    }
+}
@@ -0,0 +1,219 @@
+//! Content types of static assets and stored payloads, by the file name
+//! extension or, when there's none we know, by the first bytes
+
+/// What we send when we can't tell
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// How many bytes `sniff` needs to see to recognize everything it knows
+pub const SNIFF_LEN: usize = 512;
+
+/// Extensions in lower case. Textual types say they are UTF-8,
+/// which is what our assets and texts are
+const EXTENSIONS: &[(&str, &str)] = &[
+    // text
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml; charset=utf-8"),
+    ("json", "application/json"),
+
+    // images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("heic", "image/heic"),
+    ("svg", "image/svg+xml; charset=utf-8"),
+
+    // fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+
+    // audio and video
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("m4a", "audio/mp4"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+
+    // documents and archives
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("wasm", "application/wasm"),
+];
+
+/// Signatures at the beginning of the file. `?` matches any byte
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"RIFF????WEBP", "image/webp"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"RIFF????WAVE", "audio/wav"),
+    (b"RIFF????AVI ", "video/x-msvideo"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\0asm", "application/wasm"),
+];
+
+/// Major brands of ISO base media files, which all start with `????ftyp`.
+/// The rest are taken for MP4 video
+const FTYP_BRANDS: &[(&[u8; 4], &str)] = &[
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heic"),
+    (b"avif", "image/avif"),
+    (b"avis", "image/avif"),
+    (b"M4A ", "audio/mp4"),
+    (b"M4B ", "audio/mp4"),
+    (b"qt  ", "video/quicktime"),
+];
+
+/// Sizes of the BMP info headers that follow the file header
+const BMP_INFO_HEADER_SIZES: &[u32] = &[12, 40, 52, 56, 64, 108, 124];
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Formats whose two-byte signature or shared signature isn't enough
+fn sniff_header(head: &[u8]) -> Option<&'static str> {
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        let brand = &head[8..12];
+        return Some(FTYP_BRANDS.iter()
+                    .find(|(b, _)| &b[..] == brand)
+                    .map_or("video/mp4", |(_, content_type)| content_type));
+    }
+
+    // "BM", the file size, 4 reserved zero bytes, where the pixels
+    // start, then the info header beginning with its size
+    if head.len() >= 18 && head.starts_with(b"BM") {
+        let file_size = u32_le(&head[2..6]);
+        let reserved = u32_le(&head[6..10]);
+        let info_size = u32_le(&head[14..18]);
+        if reserved == 0 && file_size >= 14 + info_size &&
+            BMP_INFO_HEADER_SIZES.contains(&info_size)
+        {
+            return Some("image/bmp");
+        }
+    }
+
+    None
+}
+
+pub fn from_extension(filename: &str) -> Option<&'static str> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    EXTENSIONS.iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Recognizes the format by its signature, or text by it being UTF-8.
+/// HTML is never sniffed, so uploads can't turn into pages
+pub fn sniff(head: &[u8]) -> &'static str {
+    let magic = MAGIC.iter().find(|(magic, _)| {
+        head.len() >= magic.len() &&
+            magic.iter().zip(head).all(|(&m, &b)| m == b'?' || m == b)
+    });
+    if let Some((_, content_type)) = magic {
+        return content_type;
+    }
+    if let Some(content_type) = sniff_header(head) {
+        return content_type;
+    }
+
+    // the last character may be cut off
+    let text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && e.valid_up_to() + 4 > head.len(),
+    };
+    if text && !head.is_empty() && !head.contains(&0) {
+        "text/plain; charset=utf-8"
+    } else {
+        OCTET_STREAM
+    }
+}
+
+/// Type by the extension of `filename`, or by the first bytes
+/// of the content if the extension doesn't tell
+pub fn content_type(filename: &str, head: &[u8]) -> &'static str {
+    from_extension(filename).unwrap_or_else(|| sniff(head))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_from_extension() {
+        assert_eq!(from_extension("styles.css"), Some("text/css; charset=utf-8"));
+        assert_eq!(from_extension("photo.JPG"), Some("image/jpeg"));
+        assert_eq!(from_extension("archive.tar.gz"), Some("application/gzip"));
+        assert_eq!(from_extension("module.wasm"), Some("application/wasm"));
+        assert_eq!(from_extension("icon.svg"), Some("image/svg+xml; charset=utf-8"));
+        assert_eq!(from_extension("README"), None);
+        assert_eq!(from_extension("data.unknown"), None);
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), "video/mp4");
+        assert_eq!(sniff(b"\0\0\0\x18ftypheic"), "image/heic");
+        assert_eq!(sniff(b"\0\0\0\x1cftypavif"), "image/avif");
+        assert_eq!(sniff(b"\0\0\0\x20ftypM4A "), "audio/mp4");
+        assert_eq!(sniff(b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0"),
+                   "image/bmp");
+        assert_eq!(sniff(b"BMW and Audi"), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff("hello, мир".as_bytes()), "text/plain; charset=utf-8");
+        // cut in the middle of "ё"
+        assert_eq!(sniff(&"ё".as_bytes()[..1]), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"<html><script>"), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"\xff\xfe\0\x01"), OCTET_STREAM);
+        assert_eq!(sniff(b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("cat.png", b"not really a png"), "image/png");
+        assert_eq!(content_type("cat", b"GIF89a\x01\0"), "image/gif");
+    }
+}
@@ -0,0 +1,292 @@
+//! `--name-template`: names of stored payloads made of placeholders
+//! like `{date}_{orig}.{ext}`
+
+use std::net::IpAddr;
+
+/// Added to the payload name for its metadata, payload names never end
+/// with it
+pub const METADATA_SUFFIX: &str = ".metadata";
+
+/// How many numbered names are tried when the rendered one is taken
+pub const MAX_COLLISIONS: usize = 1000;
+
+/// Longest file name most file systems allow, in bytes
+const MAX_FILENAME_LEN: usize = 255;
+
+/// Rendered names leave room for the largest counter and the metadata
+/// suffix, "-999" is what `with_counter` adds at most
+const MAX_RENDERED_LEN: usize =
+    MAX_FILENAME_LEN - "-999".len() - METADATA_SUFFIX.len();
+
+/// Placeholders a template may have, for error messages
+const PLACEHOLDERS: &str =
+    "{date}, {time}, {uuid}, {orig}, {ext}, {type}, {client_ip}, {hash}";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+
+    /// 2021-03-14
+    Date,
+
+    /// 15-09-26
+    Time,
+
+    /// Random version 4 UUID
+    Uuid,
+
+    /// Sanitized name the client has sent without the extension,
+    /// the upload type if there's none
+    Orig,
+
+    /// Extension of the client's name without the dot,
+    /// "txt" for texts and "bin" for files without one
+    Ext,
+
+    /// "text" or "file"
+    Type,
+    ClientIp,
+
+    /// SHA-256 of the payload
+    Hash,
+}
+
+#[derive(Debug)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+/// What goes into a name
+pub struct NameValues<'a> {
+    pub now: chrono::DateTime<chrono::Local>,
+
+    /// Sanitized name the client has sent
+    pub name: Option<&'a str>,
+
+    /// "text" or "file"
+    pub typ: &'static str,
+
+    /// For names without one
+    pub default_extension: &'static str,
+    pub client_ip: IpAddr,
+    pub sha256: &'a str,
+}
+
+/// Cuts the string to at most `max_bytes` on a character boundary
+fn truncate(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Splits "photo.jpg" into "photo" and "jpg",
+/// names like ".bashrc" have no extension
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && dot + 1 < name.len() => {
+            (&name[..dot], Some(&name[dot + 1..]))
+        }
+        _ => (name, None),
+    }
+}
+
+fn uuid_v4() -> String {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("no random numbers");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}",
+            &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        if template.is_empty() {
+            return Err("the template is empty".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err("unmatched }".to_string());
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+
+            let end = start + rest[start..].find('}')
+                .ok_or("unclosed {")?;
+            parts.push(match &rest[start + 1..end] {
+                "date" => Part::Date,
+                "time" => Part::Time,
+                "uuid" => Part::Uuid,
+                "orig" => Part::Orig,
+                "ext" => Part::Ext,
+                "type" => Part::Type,
+                "client_ip" => Part::ClientIp,
+                "hash" => Part::Hash,
+                other => return Err(format!(
+                    "unknown placeholder {{{}}}, expected one of {}",
+                    other, PLACEHOLDERS)),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        for part in parts.iter() {
+            if let Part::Literal(literal) = part {
+                if literal.contains(|c: char| matches!(c, '/' | '\\' | '"') ||
+                                    c.is_control())
+                {
+                    return Err("it may not have /, \\, \" or control characters"
+                               .to_string());
+                }
+            }
+        }
+
+        Ok(NameTemplate { parts })
+    }
+
+    /// The name is not unique unless the template makes it so,
+    /// see `with_counter`. It doesn't end with `METADATA_SUFFIX` and
+    /// `{orig}` is cut so the name fits into 255 bytes with a counter
+    /// and the suffix added.
+    pub fn render(&self, values: &NameValues) -> String {
+        let (orig, ext) = match values.name.map(split_extension) {
+            Some((orig, ext)) => (orig, ext.unwrap_or(values.default_extension)),
+            None => (values.typ, values.default_extension),
+        };
+
+        // None stands for {orig}, which is filled in once we know
+        // how much room is left for it
+        let rendered: Vec<Option<String>> = self.parts.iter()
+            .map(|part| match part {
+                Part::Literal(literal) => Some(literal.clone()),
+                Part::Date => Some(values.now.format("%F").to_string()),
+                Part::Time => Some(values.now.format("%H-%M-%S").to_string()),
+                Part::Uuid => Some(uuid_v4()),
+                Part::Orig => None,
+                Part::Ext => Some(ext.to_string()),
+                Part::Type => Some(values.typ.to_string()),
+                Part::ClientIp => Some(values.client_ip.to_string()),
+                Part::Hash => Some(values.sha256.to_string()),
+            })
+            .collect();
+
+        let fixed_len: usize = rendered.iter().flatten().map(String::len).sum();
+        let origs = rendered.iter().filter(|part| part.is_none()).count();
+        let orig = match origs {
+            0 => orig,
+            _ => truncate(orig, MAX_RENDERED_LEN.saturating_sub(fixed_len) / origs),
+        };
+
+        let mut out: String = rendered.iter()
+            .map(|part| part.as_deref().unwrap_or(orig))
+            .collect();
+
+        // hidden files don't show up anywhere, and the name
+        // could be ".partial"
+        if out.starts_with('.') {
+            out.insert(0, '_');
+        }
+        // the rest is too long on its own
+        out.truncate(truncate(&out, MAX_RENDERED_LEN).len());
+        if out.ends_with(METADATA_SUFFIX) {
+            let dot = out.len() - METADATA_SUFFIX.len();
+            out.replace_range(dot..dot + 1, "_");
+        }
+        out
+    }
+}
+
+/// "photo.jpg" with counter 2 is "photo-2.jpg"
+pub fn with_counter(filename: &str, counter: usize) -> String {
+    match split_extension(filename) {
+        (stem, Some(ext)) => format!("{}-{}.{}", stem, counter, ext),
+        (stem, None) => format!("{}-{}", stem, counter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use chrono::TimeZone;
+
+    fn values(name: Option<&str>) -> NameValues<'_> {
+        NameValues {
+            now: chrono::Local.ymd(2021, 3, 14).and_hms(15, 9, 26),
+            name,
+            typ: "file",
+            default_extension: "bin",
+            client_ip: "192.0.2.1".parse().unwrap(),
+            sha256: "2cf24dba",
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = NameTemplate::parse(
+            "{date}_{time}_{orig}-{hash}-{client_ip}-{type}.{ext}").unwrap();
+        assert_eq!(template.render(&values(Some("cat.photo.jpg"))),
+                   "2021-03-14_15-09-26_cat.photo-2cf24dba-192.0.2.1-file.jpg");
+        assert_eq!(template.render(&values(None)),
+                   "2021-03-14_15-09-26_file-2cf24dba-192.0.2.1-file.bin");
+
+        let template = NameTemplate::parse("{orig}").unwrap();
+        assert_eq!(template.render(&values(Some(".partial"))), "_.partial");
+
+        let template = NameTemplate::parse("{orig}.{ext}").unwrap();
+        assert_eq!(template.render(&values(Some("cat.jpg.metadata"))),
+                   "cat.jpg_metadata");
+        let template = NameTemplate::parse("{orig}.metadata").unwrap();
+        assert_eq!(template.render(&values(Some("cat.jpg"))), "cat_metadata");
+
+        let template = NameTemplate::parse("{orig}.{ext}").unwrap();
+        let long = "ё".repeat(200) + ".jpg";
+        let name = template.render(&values(Some(&long)));
+        assert_eq!(name.len(), MAX_RENDERED_LEN);
+        assert!(name.starts_with("ёё") && name.ends_with("ё.jpg"));
+        let name = with_counter(&name, MAX_COLLISIONS - 1) + METADATA_SUFFIX;
+        assert!(name.len() <= MAX_FILENAME_LEN);
+
+        let template = NameTemplate::parse("{orig}-{orig}").unwrap();
+        let name = template.render(&values(Some(&"a".repeat(300))));
+        assert_eq!(name, "a".repeat(120) + "-" + &"a".repeat(120));
+
+        let template = NameTemplate::parse("{uuid}.{ext}").unwrap();
+        let name = template.render(&values(Some("cat.jpg")));
+        assert_eq!(name.len(), 36 + ".jpg".len());
+        assert_eq!(&name[14..15], "4");
+        assert_ne!(template.render(&values(Some("cat.jpg"))), name);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(NameTemplate::parse("").is_err());
+        assert!(NameTemplate::parse("{orig").is_err());
+        assert!(NameTemplate::parse("orig}").is_err());
+        assert!(NameTemplate::parse("{name}").unwrap_err().contains("{name}"));
+        assert!(NameTemplate::parse("{date}/{orig}").is_err());
+        assert!(NameTemplate::parse("{date}\\{orig}").is_err());
+        assert!(NameTemplate::parse("\"{orig}\"").is_err());
+        assert!(NameTemplate::parse("plain").is_ok());
+    }
+
+    #[test]
+    fn test_with_counter() {
+        assert_eq!(with_counter("cat.jpg", 1), "cat-1.jpg");
+        assert_eq!(with_counter("notes", 2), "notes-2");
+        assert_eq!(with_counter(".hidden", 3), ".hidden-3");
+    }
+}
@@ -0,0 +1,146 @@
+//! Progress of uploads that clients can poll at `/progress/<id>`.
+//! The id comes from the `X-Upload-Id` header or the `upload_id` query
+//! parameter of the upload request.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long finished uploads can still be looked up
+const KEEP_FINISHED: Duration = Duration::from_secs(60);
+
+const MAX_ID_LEN: usize = 64;
+
+/// Letters, digits, '-' and '_', so ids are safe to echo back
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN &&
+        id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+pub struct Progress {
+    /// Request body bytes read so far
+    received: AtomicU64,
+
+    /// Request body size if the client has told it
+    total: Option<u64>,
+
+    /// When the request has been handled
+    finished: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    fn to_json(&self, id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "received": self.received.load(Ordering::Relaxed),
+            "total": self.total,
+            "done": self.finished.lock().unwrap().is_some(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Tracker {
+    uploads: Mutex<HashMap<String, Arc<Progress>>>,
+}
+
+impl Tracker {
+    /// Tracks the upload until the guard is dropped, a previous
+    /// upload with the same id is forgotten
+    pub fn start(&self, id: &str, total: Option<u64>) -> ProgressGuard {
+        let progress = Arc::new(Progress {
+            received: AtomicU64::new(0),
+            total,
+            finished: Mutex::new(None),
+        });
+
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, progress| {
+            progress.finished.lock().unwrap()
+                .is_none_or(|finished| finished.elapsed() < KEEP_FINISHED)
+        });
+        uploads.insert(id.to_string(), Arc::clone(&progress));
+
+        ProgressGuard { progress }
+    }
+
+    pub fn get(&self, id: &str) -> Option<serde_json::Value> {
+        self.uploads.lock().unwrap().get(id).map(|progress| progress.to_json(id))
+    }
+}
+
+/// Marks the upload finished when dropped
+pub struct ProgressGuard {
+    progress: Arc<Progress>,
+}
+
+impl ProgressGuard {
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        *self.progress.finished.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+/// Counts the bytes read from the request body
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: Option<&'a Progress>,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: Option<&'a Progress>) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(progress) = self.progress {
+            progress.received.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let tracker = Tracker::default();
+        assert_eq!(tracker.get("abc"), None);
+
+        let guard = tracker.start("abc", Some(10));
+        let mut reader = ProgressReader::new(&b"hello"[..],
+                                             Some(guard.progress()));
+        reader.read_exact(&mut [0; 3]).unwrap();
+        assert_eq!(tracker.get("abc"), Some(serde_json::json!({
+            "id": "abc", "received": 3, "total": 10, "done": false,
+        })));
+
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        drop(guard);
+        let json = tracker.get("abc").unwrap();
+        assert_eq!(json["received"], 5);
+        assert_eq!(json["done"], true);
+    }
+
+    #[test]
+    fn test_is_valid_id() {
+        assert!(is_valid_id("f81d4fae-7dec_11d0"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("../etc"));
+        assert!(!is_valid_id("<script>"));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+}
@@ -0,0 +1,238 @@
+//! `--quota`: how much the uploads directory takes plus what's being
+//! received right now, so concurrent uploads can't exceed it together
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::srv::STAGING_DIR;
+
+/// How often the uploads directory is measured again, so files removed
+/// by others stop counting
+pub const MEASURE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Usage {
+    /// What the uploads directory takes, outside the staging directory
+    stored: u64,
+
+    /// Taken by uploads being received
+    reserved: u64,
+}
+
+pub struct Quota {
+    /// None if there's no quota, nothing is counted then
+    limit: Option<u64>,
+    dir: PathBuf,
+    usage: Mutex<Usage>,
+}
+
+/// Total size of files in the directory and its subdirectories but
+/// `skip`. Files with several hard links are split between the links
+/// so deduplicated payloads are only counted once.
+fn dir_size(dir: &Path, skip: Option<&str>) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if skip.is_some_and(|skip| entry.file_name() == skip) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path(), None)?;
+        } else {
+            size += metadata.len() / metadata.nlink().max(1);
+        }
+    }
+    Ok(size)
+}
+
+impl Quota {
+    /// Measures the directory right away if there's a quota
+    pub fn new<P: AsRef<Path>>(dir: P, limit: Option<u64>) -> Self {
+        let quota = Quota {
+            limit,
+            dir: dir.as_ref().to_path_buf(),
+            usage: Mutex::new(Usage::default()),
+        };
+        quota.measure();
+        quota
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// Walks the uploads directory again. Files stored while it's
+    /// walked may be counted twice or not at all until the next time.
+    pub fn measure(&self) {
+        if self.limit.is_none() {
+            return;
+        }
+
+        // files being received are reserved already
+        match dir_size(&self.dir, Some(STAGING_DIR)) {
+            Ok(size) => self.usage.lock().unwrap().stored = size,
+            Err(e) => println!("Could not tell how much {} takes: {}",
+                               self.dir.display(), e),
+        }
+    }
+
+    /// How much is left, None if there's no quota
+    pub fn remaining(&self) -> Option<u64> {
+        let limit = self.limit?;
+        let usage = self.usage.lock().unwrap();
+        Some(limit.saturating_sub(usage.stored + usage.reserved))
+    }
+
+    /// Counts `size` more bytes as stored
+    pub fn stored(&self, size: u64) {
+        if self.limit.is_some() {
+            self.usage.lock().unwrap().stored += size;
+        }
+    }
+
+    /// Counts `size` bytes that were stored as removed
+    pub fn freed(&self, size: u64) {
+        if self.limit.is_some() {
+            let mut usage = self.usage.lock().unwrap();
+            usage.stored = usage.stored.saturating_sub(size);
+        }
+    }
+
+    /// An empty reservation for an upload that's starting
+    pub fn reserve(&self) -> Reservation<'_> {
+        Reservation { quota: self, size: Cell::new(0), exceeded: Cell::new(false) }
+    }
+}
+
+/// Space taken by an upload being received, given back when dropped
+pub struct Reservation<'q> {
+    quota: &'q Quota,
+    size: Cell<u64>,
+
+    /// Whether `grow` has failed
+    exceeded: Cell<bool>,
+}
+
+impl Reservation<'_> {
+    /// Takes `size` bytes more, false if the quota doesn't have them
+    pub fn grow(&self, size: u64) -> bool {
+        let limit = match self.quota.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let mut usage = self.quota.usage.lock().unwrap();
+        if usage.stored + usage.reserved + size > limit {
+            self.exceeded.set(true);
+            return false;
+        }
+        usage.reserved += size;
+        self.size.set(self.size.get() + size);
+        true
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.get()
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.get()
+    }
+
+    /// Counts `size` bytes as stored, taking them out of the reservation
+    pub fn commit(&self, size: u64) {
+        if self.quota.limit.is_none() {
+            return;
+        }
+
+        let taken = size.min(self.size.get());
+        let mut usage = self.quota.usage.lock().unwrap();
+        usage.reserved -= taken;
+        usage.stored += size;
+        self.size.set(self.size.get() - taken);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.size.get() > 0 {
+            self.quota.usage.lock().unwrap().reserved -= self.size.get();
+        }
+    }
+}
+
+/// Reserves the bytes read from the request body, fails once
+/// the quota is exhausted
+pub struct QuotaReader<'a, R> {
+    inner: R,
+    reservation: &'a Reservation<'a>,
+}
+
+impl<'a, R: Read> QuotaReader<'a, R> {
+    pub fn new(inner: R, reservation: &'a Reservation<'a>) -> Self {
+        QuotaReader { inner, reservation }
+    }
+}
+
+impl<R: Read> Read for QuotaReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if !self.reservation.grow(read as u64) {
+            return Err(io::Error::other("upload quota is exhausted"));
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_reservations() {
+        let dir = test_dir("quota-reservations");
+        fs::write(dir.join("notes.txt"), b"1234").unwrap();
+        fs::create_dir(dir.join(STAGING_DIR)).unwrap();
+        fs::write(dir.join(STAGING_DIR).join("incomplete"), b"12").unwrap();
+        let quota = Quota::new(&dir, Some(10));
+        assert_eq!(quota.remaining(), Some(6));
+
+        // two uploads at once can't take more than what's left together
+        let first = quota.reserve();
+        let second = quota.reserve();
+        assert!(first.grow(4));
+        assert!(!second.grow(3));
+        assert!(second.exceeded());
+        assert!(second.grow(2));
+        assert_eq!(quota.remaining(), Some(0));
+
+        // a failed upload gives its space back
+        drop(second);
+        assert_eq!(quota.remaining(), Some(2));
+
+        first.commit(4);
+        drop(first);
+        assert_eq!(quota.remaining(), Some(2));
+
+        let reservation = quota.reserve();
+        let mut reader = QuotaReader::new(&b"hello"[..], &reservation);
+        assert!(io::copy(&mut reader, &mut io::sink()).is_err());
+        assert!(reservation.exceeded());
+        drop(reservation);
+
+        // files removed by others stop counting
+        fs::remove_file(dir.join("notes.txt")).unwrap();
+        quota.measure();
+        assert_eq!(quota.remaining(), Some(10));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
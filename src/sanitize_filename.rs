@@ -1,8 +1,42 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::transliterate::transliterate;
+
+/// What characters are kept in stored file names
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilenameMode {
+    /// ASCII letters, digits, '.' and '_'
+    Ascii,
+
+    /// Unicode letters and digits in NFC, '.' and '_'
+    Unicode,
+
+    /// Like `Ascii`, but Cyrillic, Greek and Latin letters with
+    /// diacritics are transliterated first
+    Transliterate,
+}
+
+/// Longest name in bytes we keep in Unicode mode, the date, time
+/// and suffixes we add have to fit into 255 bytes too
+pub const MAX_NAME_BYTES: usize = 200;
+
+/// Longest extension in bytes that's kept when the name is truncated
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Device names Windows doesn't let files have, with any extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
 enum State {
     Passed,
     Skipped,
 }
 
+#[allow(clippy::manual_is_ascii_check)]
 fn is_byte_valid_char(c: u8) -> bool {
     if (b'a'..=b'z').contains(&c) {
         return true;
@@ -50,6 +84,84 @@ pub fn sanitize_filename<T: AsRef<str>>(filename: T) -> String {
     unsafe { String::from_utf8_unchecked(out) }
 }
 
+/// `sanitize_filename` for the given `--filename-mode`
+pub fn sanitize_filename_with_mode<T: AsRef<str>>(filename: T,
+                                                  mode: FilenameMode)
+                                                  -> String {
+    match mode {
+        FilenameMode::Ascii => sanitize_filename(filename),
+        FilenameMode::Unicode => sanitize_unicode(filename.as_ref()),
+        FilenameMode::Transliterate => {
+            sanitize_filename(transliterate(filename.as_ref()))
+        }
+    }
+}
+
+fn is_valid_char(c: char) -> bool {
+    c.is_alphanumeric() || is_combining_mark(c) || c == '.' || c == '_'
+}
+
+/// Like the ASCII mode, but keeps letters and digits of any script.
+/// The result has no leading dots, isn't a reserved name and
+/// fits into `MAX_NAME_BYTES`
+fn sanitize_unicode(filename: &str) -> String {
+    let mut out = String::with_capacity(filename.len());
+    let mut state = State::Passed;
+
+    for c in filename.nfc() {
+        if is_valid_char(c) {
+            out.push(c);
+            state = State::Passed;
+        } else if let State::Passed = state {
+            state = State::Skipped;
+            out.push('_');
+        }
+    }
+
+    // dropping characters may leave marks that compose with what's
+    // before them now
+    let out: String = out.nfc().collect();
+
+    let out = out.trim_start_matches('.');
+    let mut out = if out.is_empty() {
+        "_".to_string()
+    } else if is_reserved(out) {
+        format!("_{}", out)
+    } else {
+        out.to_string()
+    };
+
+    truncate(&mut out, MAX_NAME_BYTES);
+    out
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name);
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Shortens the name to at most `max_bytes` on a character boundary,
+/// keeping the extension if it's short
+fn truncate(name: &mut String, max_bytes: usize) {
+    if name.len() <= max_bytes {
+        return;
+    }
+
+    let extension = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION_BYTES => {
+            name.split_off(dot)
+        }
+        _ => String::new(),
+    };
+
+    let mut end = max_bytes - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name.truncate(end);
+    name.push_str(&extension);
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
@@ -75,4 +187,88 @@ mod tests {
             "hello_blah_.txt".to_string()
         )
     }
+
+    #[test]
+    fn test_transliterate_mix_valid_and_invalid() {
+        assert_eq!(
+            sanitize_filename_with_mode("hello🐧ыblah Ķ.txt", FilenameMode::Transliterate),
+            "hello_yblah_K.txt".to_string()
+        );
+        assert_eq!(
+            sanitize_filename_with_mode("отчёт 2024.pdf", FilenameMode::Transliterate),
+            "otchyot_2024.pdf".to_string()
+        );
+        assert_eq!(
+            sanitize_filename_with_mode("報告書.docx", FilenameMode::Transliterate),
+            "_.docx".to_string()
+        );
+    }
+
+    fn unicode(filename: &str) -> String {
+        sanitize_filename_with_mode(filename, FilenameMode::Unicode)
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(unicode("отчёт 2024.pdf"), "отчёт_2024.pdf");
+        assert_eq!(unicode("報告書.docx"), "報告書.docx");
+        assert_eq!(unicode("hello🐧ыblah Ķ.txt"), "hello_ыblah_Ķ.txt");
+        assert_eq!(unicode("हिन्दी.txt"), "हिन्दी.txt");
+
+        // "e" followed by a combining acute accent becomes "é"
+        assert_eq!(unicode("caf\u{65}\u{301}.txt"), "caf\u{e9}.txt");
+    }
+
+    #[test]
+    fn test_unicode_rejected() {
+        assert_eq!(unicode("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(unicode("a\\b\u{0}c\nd"), "a_b_c_d");
+        assert_eq!(unicode(".bashrc"), "bashrc");
+        assert_eq!(unicode("..."), "_");
+        assert_eq!(unicode(""), "_");
+        assert_eq!(unicode("con.txt"), "_con.txt");
+        assert_eq!(unicode("LPT1"), "_LPT1");
+        assert_eq!(unicode("console.txt"), "console.txt");
+    }
+
+    #[test]
+    fn test_unicode_truncate() {
+        let name = format!("{}.pdf", "я".repeat(150));
+        let sanitized = unicode(&name);
+        assert!(sanitized.len() <= MAX_NAME_BYTES);
+        assert_eq!(sanitized, format!("{}.pdf", "я".repeat(98)));
+
+        // too long to be an extension
+        let name = format!("a.{}", "b".repeat(300));
+        assert_eq!(unicode(&name).len(), MAX_NAME_BYTES);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_unicode_is_safe(filename in "\\PC*|.*") {
+            let sanitized = unicode(&filename);
+
+            proptest::prop_assert!(!sanitized.is_empty());
+            proptest::prop_assert!(sanitized.len() <= MAX_NAME_BYTES);
+            proptest::prop_assert!(!sanitized.starts_with('.'));
+            proptest::prop_assert!(!is_reserved(&sanitized));
+            proptest::prop_assert!(sanitized.chars().all(
+                |c| !c.is_control() && c != '/' && c != '\\'));
+            proptest::prop_assert!(
+                unicode_normalization::is_nfc(&sanitized));
+        }
+
+        #[test]
+        fn prop_unicode_is_idempotent(filename in "\\PC*") {
+            let sanitized = unicode(&filename);
+            proptest::prop_assert_eq!(unicode(&sanitized), sanitized);
+        }
+
+        #[test]
+        fn prop_unicode_keeps_letters(filename in "[\\p{L}\\p{Nd}]{1,60}") {
+            let filename: String = filename.nfc().collect();
+            proptest::prop_assume!(!is_reserved(&filename));
+            proptest::prop_assert_eq!(unicode(&filename), filename);
+        }
+    }
 }
@@ -1,21 +1,39 @@
 use std::fmt;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, Write};
 use std::path;
 
 use std::io::Cursor;
 use url::Url;
+use url::percent_encoding::percent_decode;
 
 use std::process;
 use std::thread;
 use std::time;
 use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TrySendError};
 
 use crate::config;
 
-use multipart::server::{Multipart, SaveResult};
-
-use crate::sanitize_filename::sanitize_filename;
+use multipart::server::{FieldHeaders, Multipart, MultipartData, ReadEntry, SaveResult};
+use multipart::server::save::PartialReason;
+
+use crate::sanitize_filename::sanitize_filename_with_mode;
+use crate::tus;
+use crate::hash;
+use crate::hooks;
+use crate::metrics;
+use crate::mime;
+use crate::name_template;
+use crate::progress;
+use crate::quota;
+use crate::webhook;
+use crate::access_log;
+use crate::auth;
 
 #[derive(RustEmbed)]
 #[folder = "assets"]
@@ -23,20 +41,251 @@ struct StaticAsset;
 
 use crate::error::{Error, ErrorKind};
 
+fn header<T: fmt::Display>(field: &str, value: T) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(field.as_bytes(), value.to_string().as_bytes())
+        .unwrap()
+}
+
 fn content_type_header(value: &str) -> tiny_http::Header {
-    tiny_http::Header::from_bytes(&b"Content-Type"[..], value).unwrap()
+    header("Content-Type", value)
+}
+
+fn json_response(status: u16, json: &serde_json::Value)
+                 -> tiny_http::Response<Cursor<Cow<'static, [u8]>>> {
+    let mut data = json.to_string().into_bytes();
+    data.push(b'\n');
+    tiny_http::Response::new(
+        tiny_http::StatusCode(status),
+        vec![content_type_header("application/json")],
+        Cursor::new(Cow::from(data)),
+        None,
+        None,
+    )
+}
+
+/// File name from a Content-Disposition header like
+/// `attachment; filename="cat.jpg"` or `filename*=UTF-8''%D0%BA%D0%BE%D1%82.jpg`
+fn content_disposition_filename(value: &str) -> Option<String> {
+    let mut filename = None;
+
+    for param in value.split(';').skip(1) {
+        let (key, value) = match param.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+
+        if key.eq_ignore_ascii_case("filename*") {
+            // the extended form wins over the plain one,
+            // unless we can't decode it
+            if let Some(decoded) = decode_ext_value(value) {
+                return Some(decoded);
+            }
+        } else if key.eq_ignore_ascii_case("filename") {
+            let value = value.strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            filename = Some(value.replace("\\\"", "\""));
+        }
+    }
+
+    filename.filter(|name| !name.is_empty())
+}
+
+/// Decodes an RFC 5987 value like `UTF-8''%D0%BA%D0%BE%D1%82.jpg`,
+/// None if it's malformed or not UTF-8
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+    let decoded = percent_decode(encoded.as_bytes()).decode_utf8().ok()?;
+    Some(decoded.into_owned()).filter(|name| !name.is_empty())
+}
+
+/// Prefix for requests that want JSON responses no matter what they accept
+const API_PREFIX: &str = "/api/v1/";
+
+/// Whether the client wants JSON instead of HTML pages
+fn is_api_request(req: &tiny_http::Request) -> bool {
+    req.url().starts_with(API_PREFIX) ||
+        header_value(req, "Accept")
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+/// First path segment of the URL, the label for the metrics.
+/// Anything we don't serve is "other"
+fn route_name(base_url: &Url, url: &str) -> &'static str {
+    let url = match base_url.join(url) {
+        Ok(url) => url,
+        Err(_) => return "other",
+    };
+    let mut path_segments = match url.path_segments() {
+        Some(segments) => segments,
+        None => return "other",
+    };
+    // the home page isn't served under the API prefix
+    let api = url.path().starts_with(API_PREFIX);
+    if api {
+        path_segments.nth(1);
+    }
+
+    match path_segments.next() {
+        Some("") if !api => "home",
+        Some("assets") => "assets",
+        Some("text") => "text",
+        Some("file") => "file",
+        Some("tus") => "tus",
+        Some("files") => "files",
+        Some("metrics") => "metrics",
+        Some("healthz") => "healthz",
+        Some("readyz") => "readyz",
+        Some("progress") => "progress",
+        _ => "other",
+    }
+}
+
+/// Routes that receive payloads
+fn is_upload_route(route: &str) -> bool {
+    matches!(route, "text" | "file" | "tus")
+}
+
+/// Like `tiny_http::Request::respond`, but also tells the status code
+/// and how many bytes have been sent
+fn send_response<R: io::Read>(req: tiny_http::Request,
+                              resp: tiny_http::Response<R>)
+    -> (io::Result<()>, access_log::ResponseWriter<Box<dyn Write + Send>>)
+{
+    let http_version = req.http_version().clone();
+    let headers = req.headers().to_vec();
+    let do_not_send_body = req.method() == &tiny_http::Method::Head;
+
+    let mut writer = access_log::ResponseWriter::new(req.into_writer());
+    let result = match resp.raw_print(&mut writer, http_version, &headers,
+                                      do_not_send_body, None) {
+        // the client has gone away, nothing we can do about it
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe ||
+            e.kind() == io::ErrorKind::ConnectionAborted ||
+            e.kind() == io::ErrorKind::ConnectionReset => Ok(()),
+        result => result,
+    };
+    (result.and_then(|()| writer.flush()), writer)
+}
+
+fn www_authenticate_header(value: &str) -> tiny_http::Header {
+    header("WWW-Authenticate", value)
 }
 
+fn header_value<'r>(req: &'r tiny_http::Request, field: &'static str)
+                    -> Option<&'r str> {
+    req.headers().iter()
+        .find(|h| h.field.equiv(field))
+        .map(|h| h.value.as_str())
+}
+
+/// "xyz" from "multipart/form-data; boundary=xyz"
+fn multipart_boundary(req: &tiny_http::Request) -> Option<&str> {
+    const BOUNDARY: &str = "boundary=";
+
+    let content_type = header_value(req, "Content-Type")?;
+    let start = content_type.find(BOUNDARY)? + BOUNDARY.len();
+    let end = content_type[start..].find(';')
+        .map_or(content_type.len(), |end| start + end);
+    Some(&content_type[start..end])
+}
+
+thread_local! {
+    /// Payloads stored while handling the current request, for the access
+    /// log. Every request is handled on a single worker thread.
+    static STORED_FILENAMES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+
+    /// Request body bytes read while handling the current request,
+    /// see `body_reader`
+    static BYTES_RECEIVED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Reads the request body, counting the bytes for the access log
+struct BodyReader<'a> {
+    inner: &'a mut dyn Read,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        BYTES_RECEIVED.with(|received| received.set(received.get() + read as u64));
+        Ok(read)
+    }
+}
+
+/// The request body, read it only through this
+fn body_reader(req: &mut tiny_http::Request) -> BodyReader<'_> {
+    BodyReader { inner: req.as_reader() }
+}
+
+/// How often we check whether we've been asked to shut down
+const SHUTDOWN_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
 pub struct Srv<'config> {
     http: tiny_http::Server,
     base_url: Url,
     html_content_type: tiny_http::Header,
     die_after_single_request: bool,
 
+    /// Partial resumable uploads
+    tus: tus::Staging,
+
+    /// Runs --on-upload if it's set
+    hooks: Option<hooks::Hooks>,
+
+    /// Sends --webhook events if it's set
+    webhook: Option<webhook::Webhook>,
+
+    /// Set on SIGTERM or SIGINT, we stop accepting requests then
+    shutdown: Arc<AtomicBool>,
+
+    /// What the workers are busy with
+    activity: Mutex<Activity>,
+
+    /// Notified when the last request being handled is done
+    idle: Condvar,
+
+    /// Served at /metrics
+    metrics: metrics::Metrics,
+
+    /// Served at /progress/<id>
+    progress: progress::Tracker,
+
+    /// What the uploads directory and the uploads being received take,
+    /// for --quota
+    quota: quota::Quota,
+
     config: &'config config::Config,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// What the workers are busy with, so we know what to wait for
+/// when shutting down
+#[derive(Default)]
+struct Activity {
+    /// How many requests are being handled
+    requests: usize,
+
+    /// Payloads that are being received
+    incomplete: HashSet<path::PathBuf>,
+}
+
+/// Keeps the payload in `Activity::incomplete` while it's alive
+struct IncompleteGuard<'a> {
+    activity: &'a Mutex<Activity>,
+    path: path::PathBuf,
+}
+
+impl Drop for IncompleteGuard<'_> {
+    fn drop(&mut self) {
+        self.activity.lock().unwrap().incomplete.remove(&self.path);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum UploadType {
     Text,
     File,
@@ -67,12 +316,36 @@ impl UploadType {
         }
     }
 
+    /// For --name-template when the client's name has no extension
+    fn default_extension(self) -> &'static str {
+        match self {
+            UploadType::Text => "txt",
+            UploadType::File => "bin",
+        }
+    }
+
     fn as_file_suffix(self) -> &'static str {
         match self {
             UploadType::Text => "text.txt",
             UploadType::File => "file.bin",
         }
     }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(UploadType::Text),
+            "file" => Some(UploadType::File),
+            _ => None,
+        }
+    }
+
+    fn from_file_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "text.txt" => Some(UploadType::Text),
+            "file.bin" => Some(UploadType::File),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for UploadType {
@@ -81,27 +354,23 @@ impl fmt::Display for UploadType {
     }
 }
 
-fn filename_to_content_type<T: AsRef<str>>(filename: T) -> &'static str {
-    let filename = filename.as_ref();
-    let extension: Option<&str> = filename.split('.').last();
-
-    const DEFAULT_CONTENT_TYPE: &str = "text/plain";
-    match extension {
-        Some("css") => "text/css",
-        Some("js") => "text/javascript",
-        Some("html") => "text/html",
-        Some(_) => DEFAULT_CONTENT_TYPE,
-        None => DEFAULT_CONTENT_TYPE,
-    }
-}
-
 /// A type of file that we store on the filesystem
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum FileType {
     Payload,
     Metadata,
 }
 
+impl FileType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "payload" => Some(FileType::Payload),
+            "metadata" => Some(FileType::Metadata),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for FileType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -128,6 +397,252 @@ fn mangle_filename<T: AsRef<str>>(
     }
 }
 
+/// What `mangle_filename` has put into the name of a stored file
+#[derive(Debug, PartialEq)]
+struct MangledFilename<'a> {
+    timestamp: chrono::NaiveDateTime,
+    name: Option<&'a str>,
+    typ: UploadType,
+    file_type: FileType,
+}
+
+/// The reverse of `mangle_filename`.
+/// Returns None for files that don't look like something we've stored.
+fn parse_mangled_filename(filename: &str) -> Option<MangledFilename<'_>> {
+    let mut parts = filename.splitn(3, "--");
+    let date = parts.next()?;
+    let time = parts.next()?;
+    let rest = parts.next()?;
+
+    // the original name goes in the middle, so take the rest from the end
+    let mut parts = rest.rsplitn(3, "--");
+    let file_type = FileType::from_name(parts.next()?)?;
+    let typ = UploadType::from_file_suffix(parts.next()?)?;
+    let name = parts.next();
+
+    let timestamp = chrono::NaiveDateTime::parse_from_str(
+        &format!("{}--{}", date, time), "%F--%T.%f").ok()?;
+
+    Some(MangledFilename { timestamp, name, typ, file_type })
+}
+
+impl SavedFile {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "stored_filename": self.stored_filename,
+            "size": self.size,
+            "sha256": self.sha256,
+            "duplicate": self.duplicate,
+        })
+    }
+}
+
+/// Content-addressed copies of payloads for --dedup,
+/// inside the uploads directory
+const BLOBS_DIR: &str = ".blobs";
+
+/// Files are written here and moved into the uploads directory once
+/// complete, so nothing watching it sees them half-written
+pub const STAGING_DIR: &str = ".partial";
+
+/// Files named after the payloads stored with --name-template, since
+/// their names can't tell them from anything else in the uploads
+/// directory. They hold the type and time of the upload,
+/// see `mark_templated`
+const TEMPLATED_DIR: &str = ".templated";
+
+/// Content-Disposition value with the file name, which is sent
+/// percent-encoded in filename* if it isn't ASCII
+fn content_disposition(disposition: &str, filename: &str) -> String {
+    if filename.is_ascii() {
+        return format!("{}; filename=\"{}\"", disposition, filename);
+    }
+
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for &b in filename.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}",
+            disposition, fallback, encoded)
+}
+
+/// Space available to us on the file system that has `path`
+fn free_space(path: &path::Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn quota_exhausted() -> Error {
+    Error::new(ErrorKind::PayloadTooLarge, "Upload quota is exhausted")
+}
+
+fn too_large(limit: u64) -> Error {
+    Error::new(ErrorKind::PayloadTooLarge,
+               format!("Upload is larger than {} bytes", limit))
+}
+
+/// The parts of a request that go into metadata files.
+/// Multipart parsing borrows the request, so we take them beforehand.
+struct RequestInfo {
+    method: tiny_http::Method,
+    http_version: tiny_http::HTTPVersion,
+    remote_addr: std::net::SocketAddr,
+    headers: Vec<tiny_http::Header>,
+}
+
+/// Headers with credentials, they aren't written to metadata files
+const SECRET_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie"];
+
+impl RequestInfo {
+    fn new(req: &tiny_http::Request) -> Self {
+        RequestInfo {
+            method: req.method().clone(),
+            http_version: req.http_version().clone(),
+            remote_addr: *req.remote_addr().expect("we only listen on TCP"),
+            headers: req.headers().to_vec(),
+        }
+    }
+
+    /// Headers that may be stored on disk
+    fn public_headers(&self) -> impl Iterator<Item = &tiny_http::Header> {
+        self.headers.iter().filter(|h| {
+            !SECRET_HEADERS.iter().any(|&secret| h.field.equiv(secret))
+        })
+    }
+}
+
+/// What the name of a stored payload is made of
+struct PayloadName<'a> {
+    now: chrono::DateTime<chrono::Local>,
+    typ: UploadType,
+
+    /// Sanitized name the client has sent
+    name: Option<&'a str>,
+    client_ip: std::net::IpAddr,
+}
+
+/// A payload that's been written completely
+struct StoredPayload<'a> {
+    now: chrono::DateTime<chrono::Local>,
+    typ: UploadType,
+
+    /// Name of the payload in the uploads directory
+    stored_filename: &'a str,
+
+    /// The name the client has sent
+    original_name: Option<&'a str>,
+
+    /// Content type the client has sent
+    content_type: Option<&'a str>,
+
+    size: u64,
+    sha256: String,
+}
+
+impl StoredPayload<'_> {
+    fn to_json(&self, req: &RequestInfo) -> serde_json::Value {
+        // repeated headers are combined like HTTP allows
+        let mut headers = serde_json::Map::new();
+        for h in req.public_headers() {
+            let field = h.field.as_str().as_str().to_string();
+            let value = match headers.remove(&field) {
+                Some(serde_json::Value::String(prev)) => {
+                    format!("{}, {}", prev, h.value)
+                }
+                _ => h.value.as_str().to_string(),
+            };
+            headers.insert(field, serde_json::Value::String(value));
+        }
+
+        serde_json::json!({
+            "timestamp": self.now.to_rfc3339(),
+            "remote_addr": req.remote_addr.to_string(),
+            "method": req.method.as_str(),
+            "headers": headers,
+            "upload_type": self.typ.name(),
+            "original_filename": self.original_name,
+            "stored_filename": self.stored_filename,
+            "size": self.size,
+            "content_type": self.content_type,
+            "sha256": self.sha256,
+        })
+    }
+}
+
+/// What happened to one of the files uploaded in a single request
+struct FileResult {
+    name: String,
+    result: Result<SavedFile, Error>,
+}
+
+impl FileResult {
+    fn to_json(&self) -> serde_json::Value {
+        match &self.result {
+            Ok(saved) => {
+                let mut json = saved.to_json();
+                json["name"] = self.name.clone().into();
+                json["status"] = ErrorKind::Success.as_http_code().into();
+                json
+            }
+            Err(err) => {
+                let mut json = err.to_json();
+                json["name"] = self.name.clone().into();
+                json
+            }
+        }
+    }
+}
+
+/// A payload that's been saved successfully
+struct SavedFile {
+    /// Name of the payload in the uploads directory
+    stored_filename: String,
+    size: u64,
+    sha256: String,
+
+    /// Whether the same content had been uploaded before
+    duplicate: bool,
+}
+
+/// A payload in the uploads directory
+struct StoredFile {
+    filename: String,
+    timestamp: chrono::NaiveDateTime,
+    name: Option<String>,
+    typ: UploadType,
+    size: u64,
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 impl<'config> Srv<'config> {
     pub fn new(http: tiny_http::Server,
                base_url: Url,
@@ -138,10 +653,65 @@ impl<'config> Srv<'config> {
             base_url,
             html_content_type: content_type_header("text/html"),
             die_after_single_request: false,
+            tus: tus::Staging::new(&config.uploads_dir),
+            hooks: config.on_upload.as_ref().map(|command| {
+                hooks::Hooks::start(command, config.hook_timeout,
+                                    config.hook_concurrency)
+            }),
+            webhook: config.webhook.clone().map(webhook::Webhook::start),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            activity: Mutex::new(Activity::default()),
+            idle: Condvar::new(),
+            metrics: metrics::Metrics::default(),
+            progress: progress::Tracker::default(),
+            quota: quota::Quota::new(&config.uploads_dir, config.quota),
             config,
         }
     }
 
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Makes `run` return gracefully on SIGTERM or SIGINT.
+    /// More signals don't hurry it, the uploads being received still get
+    /// --shutdown-timeout to finish and incomplete ones are cleaned up.
+    /// SIGHUP reopens the access log.
+    pub fn handle_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        use signal_hook::flag;
+        use signal_hook::iterator::Signals;
+
+        if let Some(access_log) = &self.config.access_log {
+            flag::register(SIGHUP, access_log.reopen_flag())?;
+        }
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = Arc::clone(&self.shutdown);
+        let timeout = self.config.shutdown_timeout;
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.swap(true, Ordering::Relaxed) {
+                    println!("Got signal {} while shutting down, still \
+                              waiting up to {} s for uploads to finish",
+                             signal, timeout.as_secs());
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Remembers that the payload is being received until the guard
+    /// is dropped, so it can be removed if we exit before it's complete
+    fn mark_incomplete(&self, path: &path::Path) -> IncompleteGuard<'_> {
+        self.activity.lock().unwrap().incomplete.insert(path.to_path_buf());
+        IncompleteGuard { activity: &self.activity, path: path.to_path_buf() }
+    }
+
     fn die_if_single_request(&self) {
         if self.die_after_single_request {
             // die after a few ms to be restarted by bash script
@@ -158,34 +728,315 @@ impl<'config> Srv<'config> {
     }
 
 
-    fn create_file<T: AsRef<str>>(
+    fn stored_path<T: AsRef<str>>(
         &self, now: chrono::DateTime<chrono::Local>, typ: UploadType,
-        file_type: FileType, name: Option<T>) -> io::Result<fs::File>
+        file_type: FileType, name: Option<T>) -> path::PathBuf
     {
         let filename = mangle_filename(now, typ, file_type, name);
-        let path = path::Path::new(&self.config.uploads_dir).join(filename);
+        path::Path::new(&self.config.uploads_dir).join(filename)
+    }
+
+    /// Where the file at `path` is written before it's complete
+    fn staging_path(&self, path: &path::Path) -> path::PathBuf {
+        let filename = path.file_name().expect("stored paths have file names");
+        path::Path::new(&self.config.uploads_dir)
+            .join(STAGING_DIR)
+            .join(filename)
+    }
+
+    /// Creates the file in the staging directory,
+    /// `publish` moves it to `path` when it's complete
+    fn create_file(&self, path: &path::Path) -> io::Result<fs::File> {
+        if path.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        let staging_path = self.staging_path(path);
+        fs::create_dir_all(staging_path.parent().unwrap())?;
         fs::OpenOptions::new()
             .read(false)
             .write(true)
             .create_new(true)
-            .open(path)
+            .open(staging_path)
+    }
+
+    /// Moves a complete file from the staging directory to `path`
+    fn publish(&self, staging_path: &path::Path, path: &path::Path)
+               -> Result<(), Error> {
+        // unlike rename, hard_link refuses to overwrite an existing file
+        if let Err(e) = fs::hard_link(staging_path, path) {
+            self.remove_staged(staging_path);
+            return Err(Error::from_io_error(e, "store file"));
+        }
+        self.published(staging_path)
+    }
+
+    /// Removes the staged name of a file that's been linked into place
+    fn published(&self, staging_path: &path::Path) -> Result<(), Error> {
+        self.remove_staged(staging_path);
+
+        // make sure the new name survives a crash too
+        fs::File::open(&self.config.uploads_dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| Error::from_io_error(e, "sync uploads directory"))
+    }
+
+    /// Name the payload gets in the uploads directory, with
+    /// --name-template it may be taken already
+    fn payload_filename(&self, name: &PayloadName, sha256: &str) -> String {
+        match &self.config.name_template {
+            None => mangle_filename(
+                name.now, name.typ, FileType::Payload, name.name),
+            Some(template) => template.render(&name_template::NameValues {
+                now: name.now,
+                name: name.name,
+                typ: name.typ.name(),
+                default_extension: name.typ.default_extension(),
+                client_ip: name.client_ip,
+                sha256,
+            }),
+        }
+    }
+
+    fn metadata_filename(&self, payload_filename: &str) -> String {
+        match &self.config.name_template {
+            None => {
+                let payload = FileType::Payload.to_string();
+                let stem = payload_filename.strip_suffix(&payload)
+                    .unwrap_or(payload_filename);
+                format!("{}{}", stem, FileType::Metadata)
+            }
+            Some(_) => format!(
+                "{}{}", payload_filename, name_template::METADATA_SUFFIX),
+        }
+    }
+
+    /// When and of what type the payload stored under --name-template
+    /// has been uploaded, None if the file isn't one, see `mark_templated`
+    fn templated_payload(&self, filename: &str)
+                         -> Option<(chrono::NaiveDateTime, UploadType)> {
+        if self.config.name_template.is_none() || filename.starts_with('.') {
+            return None;
+        }
+
+        let marker = fs::read_to_string(
+            path::Path::new(&self.config.uploads_dir)
+                .join(TEMPLATED_DIR)
+                .join(filename)).ok()?;
+        let (typ, timestamp) = marker.trim_end().split_once(' ')?;
+        let timestamp = chrono::NaiveDateTime::parse_from_str(
+            timestamp, "%F--%T.%f").ok()?;
+        Some((timestamp, UploadType::from_name(typ)?))
+    }
+
+    /// Records that the file is a payload stored under --name-template,
+    /// with the type and time of the upload its name may not tell.
+    /// A payload that isn't recorded is only left out of /files.
+    fn mark_templated(&self, filename: &str, name: &PayloadName) {
+        let dir = path::Path::new(&self.config.uploads_dir).join(TEMPLATED_DIR);
+        let marker = format!("{} {}\n", name.typ, name.now.format("%F--%T.%f"));
+        let marked = fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(dir.join(filename), marker));
+        if let Err(e) = marked {
+            println!("Could not record {} as stored: {}", filename, e);
+        }
     }
 
-    fn write_text(
-        &self, now: chrono::DateTime<chrono::Local>,
-        text: &str) -> io::Result<()>
+    /// Moves the complete payload from the staging directory into the
+    /// uploads directory as `filename`. With --name-template a number is
+    /// added to the name if it or the name of its metadata is taken.
+    /// Returns the name it's got.
+    fn publish_payload(&self, staging_path: &path::Path, name: &PayloadName,
+                       filename: String) -> Result<String, Error> {
+        let dir = path::Path::new(&self.config.uploads_dir);
+        if self.config.name_template.is_none() {
+            self.publish(staging_path, &dir.join(&filename))?;
+            return Ok(filename);
+        }
+
+        for counter in 0..name_template::MAX_COLLISIONS {
+            let candidate = match counter {
+                0 => filename.clone(),
+                _ => name_template::with_counter(&filename, counter),
+            };
+            // payload names never end with the metadata suffix, so only
+            // leftovers of deleted payloads can be in the way
+            if self.config.save_metadata &&
+                dir.join(self.metadata_filename(&candidate)).exists()
+            {
+                continue;
+            }
+            match fs::hard_link(staging_path, dir.join(&candidate)) {
+                Ok(()) => {
+                    self.published(staging_path)?;
+                    self.mark_templated(&candidate, name);
+                    return Ok(candidate);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    self.remove_staged(staging_path);
+                    return Err(Error::from_io_error(e, "store file"));
+                }
+            }
+        }
+
+        self.remove_staged(staging_path);
+        Err(Error::new(ErrorKind::Conflict, format!(
+            "{} and {} numbered names like it are taken",
+            filename, name_template::MAX_COLLISIONS - 1)))
+    }
+
+    fn remove_staged(&self, staging_path: &path::Path) {
+        if let Err(e) = fs::remove_file(staging_path) {
+            println!("Could not remove {}: {}", staging_path.display(), e);
+        }
+    }
+
+    /// Flushes the payload written into `staging_path` to disk,
+    /// deduplicates it and moves it into the uploads directory.
+    /// Its size is taken out of the reservation and counted as stored.
+    fn store_payload(&self, file: hash::Sha256Writer<fs::File>,
+                     staging_path: &path::Path, name: &PayloadName,
+                     reservation: &quota::Reservation)
+                     -> Result<SavedFile, Error> {
+        let result = file.get_ref().sync_all()
+            .map_err(|e| Error::from_io_error(e, "write file"))
+            .and_then(|()| {
+                let (size, sha256) = file.finish();
+                let duplicate = self.dedup_payload(staging_path, &sha256)?;
+                Ok((size, sha256, duplicate))
+            });
+
+        match result {
+            Ok((size, sha256, duplicate)) => {
+                let stored_filename = self.publish_payload(
+                    staging_path, name, self.payload_filename(name, &sha256))?;
+                if !duplicate {
+                    reservation.commit(size);
+                }
+                Ok(SavedFile { stored_filename, size, sha256, duplicate })
+            }
+            Err(err) => {
+                self.remove_staged(staging_path);
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes whatever was left in the staging directory by a crash
+    /// or a kill
+    fn clean_staging_dir(&self) {
+        let staging_dir =
+            path::Path::new(&self.config.uploads_dir).join(STAGING_DIR);
+        let entries = match fs::read_dir(&staging_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                println!("Could not read {}: {}", staging_dir.display(), e);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Removed incomplete {}", path.display()),
+                Err(e) => println!("Could not remove incomplete {}: {}",
+                                   path.display(), e),
+            }
+        }
+    }
+
+    /// Like `create_file`, but takes the current time itself and tries
+    /// again with a new one if the name is already taken
+    fn create_unique_file<T: AsRef<str>>(
+        &self, typ: UploadType, file_type: FileType, name: Option<T>)
+        -> io::Result<(chrono::DateTime<chrono::Local>, fs::File)>
     {
-        let mut file = self.create_file::<&str>(
-            now, UploadType::Text, FileType::Payload, None)?;
-        let bytes: &[u8] = text.as_bytes();
-        file.write_all(bytes)?;
+        const MAX_ATTEMPTS: usize = 10;
 
-        Ok(())
+        let mut attempt = 1;
+        loop {
+            let now: chrono::DateTime<chrono::Local> =
+                chrono::offset::Local::now();
+            let path = self.stored_path(now, typ, file_type, name.as_ref());
+            match self.create_file(&path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists &&
+                    attempt < MAX_ATTEMPTS => attempt += 1,
+                result => return result.map(|file| (now, file)),
+            }
+        }
+    }
+
+    /// How much is left of the quota, None if there's no quota
+    fn remaining_quota(&self) -> Result<Option<u64>, Error> {
+        match self.quota.remaining() {
+            Some(0) => Err(quota_exhausted()),
+            remaining => Ok(remaining),
+        }
+    }
+
+    /// How large a single upload may be: the smallest of `max_size`
+    /// and what's left of the quota. None if there's no limit.
+    fn upload_limit(&self, max_size: Option<u64>) -> Result<Option<u64>, Error> {
+        let remaining = self.remaining_quota()?;
+        Ok(match (max_size, remaining) {
+            (Some(max_size), Some(remaining)) => Some(max_size.min(remaining)),
+            (max_size, remaining) => max_size.or(remaining),
+        })
+    }
+
+    /// With --dedup, makes the payload a hard link to a blob named after
+    /// its hash, so identical payloads take space only once.
+    /// Returns whether the blob already existed.
+    fn dedup_payload(&self, path: &path::Path, sha256: &str)
+                     -> Result<bool, Error> {
+        if !self.config.dedup {
+            return Ok(false);
+        }
+
+        let blobs_dir = path::Path::new(&self.config.uploads_dir).join(BLOBS_DIR);
+        fs::create_dir_all(&blobs_dir)
+            .map_err(|e| Error::from_io_error(e, "create blobs directory"))?;
+        let blob = blobs_dir.join(sha256);
+
+        match fs::hard_link(path, &blob) {
+            Ok(()) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // replace the payload with a link to the existing blob
+                let mut tmp = path.as_os_str().to_owned();
+                tmp.push(".dedup");
+                fs::hard_link(&blob, &tmp)
+                    .and_then(|()| fs::rename(&tmp, path))
+                    .map_err(|e| Error::from_io_error(e, "link duplicate"))?;
+                Ok(true)
+            }
+            Err(e) => Err(Error::from_io_error(e, "create blob")),
+        }
+    }
+
+    fn write_text(&self, name: &PayloadName, text: &str,
+                  reservation: &quota::Reservation)
+                  -> Result<SavedFile, Error>
+    {
+        let path = self.stored_path::<&str>(
+            name.now, UploadType::Text, FileType::Payload, None);
+        let file = self.create_file(&path)
+            .map_err(|e| Error::from_io_error(e, "Write error"))?;
+        let staging_path = self.staging_path(&path);
+
+        let mut file = hash::Sha256Writer::new(file);
+        if let Err(e) = file.write_all(text.as_bytes()) {
+            self.remove_staged(&staging_path);
+            return Err(Error::from_io_error(e, "Write error"));
+        }
+
+        self.store_payload(file, &staging_path, name, reservation)
     }
 
     // TODO cache the content with replaced name
     fn handle_home(&self) ->
-        Result<tiny_http::Response<Cursor<Cow<[u8]>>>, Error>
+        Result<tiny_http::Response<Cursor<Cow<'_, [u8]>>>, Error>
     {
         const HOME_FILENAME: &str = "home.html";
         let content = StaticAsset::get("home.html")
@@ -199,6 +1050,14 @@ impl<'config> Srv<'config> {
             search_and_replace(
                 content, b"#{name}", self.config.send_to_name.as_bytes())
         };
+        let files_link: &[u8] = if self.config.list_uploads {
+            br#"<a href="/files">See what's been uploaded</a>"#
+        } else {
+            b""
+        };
+        let content = unsafe {
+            search_and_replace(content, b"#{files_link}", files_link)
+        };
         let content = Cow::from(content);
 
         let content_type = content_type_header("text/html");
@@ -212,14 +1071,39 @@ impl<'config> Srv<'config> {
             None,
         ))}
 
-    fn error_response(&self, err: &Error) -> tiny_http::Response<Cursor<Cow<[u8]>>> {
-        let data = format!(
-            r#"
-<html>
-  <body style="font-size: 48px">
-    {}
+    fn error_response(&self, err: &Error, json: bool)
+                      -> tiny_http::Response<Cursor<Cow<'static, [u8]>>> {
+        let mut resp = if json {
+            json_response(err.as_http_code(), &err.to_json())
+        } else {
+            self.html_error_response(err)
+        };
 
-    <br/>
+        if let (ErrorKind::Unauthorized, Some(auth)) =
+            (err.kind, &self.config.auth)
+        {
+            if auth.accepts_basic() {
+                resp.add_header(www_authenticate_header(
+                    r#"Basic realm="upload-server", charset="UTF-8""#));
+            }
+            if auth.accepts_bearer() {
+                resp.add_header(www_authenticate_header(
+                    r#"Bearer realm="upload-server""#));
+            }
+        }
+
+        resp
+    }
+
+    fn html_error_response(&self, err: &Error)
+                           -> tiny_http::Response<Cursor<Cow<'static, [u8]>>> {
+        let data = format!(
+            r#"
+<html>
+  <body style="font-size: 48px">
+    {}
+
+    <br/>
     <a href="/">Go back</a>
   </body>
 </html>
@@ -236,15 +1120,13 @@ impl<'config> Srv<'config> {
     }
 
     fn handle_static_asset(&self, filename: &str) ->
-        Result<tiny_http::Response<Cursor<Cow<[u8]>>>, Error>
+        Result<tiny_http::Response<Cursor<Cow<'_, [u8]>>>, Error>
     {
         match StaticAsset::get(filename) {
             Some(content) => {
-                let content_type = filename_to_content_type(filename);
-
-                // there must be a better way
-                let content = content;
-                let content_type = content_type_header(content_type);
+                let head = &content[..content.len().min(mime::SNIFF_LEN)];
+                let content_type = content_type_header(
+                    mime::content_type(filename, head));
 
                 let cur = Cursor::new(content);
 
@@ -265,41 +1147,320 @@ impl<'config> Srv<'config> {
         }
     }
 
-    fn write_metadata<S: AsRef<str>>(
-        &self, now: chrono::DateTime<chrono::Local>,
-        upload_type: UploadType, name: Option<S>,
-        req: &tiny_http::Request) -> Result<(), Error>
+    /// Payloads in the uploads directory, newest first
+    fn stored_files(&self) -> io::Result<Vec<StoredFile>> {
+        let mut files = Vec::new();
+
+        for entry in fs::read_dir(&self.config.uploads_dir)? {
+            let entry = entry?;
+            let filename = match entry.file_name().into_string() {
+                Ok(filename) => filename,
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let (timestamp, name, typ) = match parse_mangled_filename(&filename) {
+                Some(m) if m.file_type == FileType::Payload => {
+                    (m.timestamp, m.name.map(str::to_string), m.typ)
+                }
+                // templated names tell nothing for sure
+                _ => match self.templated_payload(&filename) {
+                    Some((timestamp, typ)) => {
+                        (timestamp, Some(filename.clone()), typ)
+                    }
+                    None => continue,
+                },
+            };
+
+            files.push(StoredFile {
+                filename, timestamp, name, typ, size: metadata.len(),
+            });
+        }
+
+        files.sort_by_key(|file| std::cmp::Reverse(file.timestamp));
+        Ok(files)
+    }
+
+    /// Whether we can store uploads, 503 if we can't
+    fn handle_readyz(&self) -> tiny_http::Response<Cursor<Cow<'static, [u8]>>> {
+        let mut ready = true;
+        let mut checks = serde_json::Map::new();
+        let mut check = |name: &str, result: Result<(), String>| {
+            ready &= result.is_ok();
+            checks.insert(name.to_string(),
+                          result.err().unwrap_or_else(|| "ok".to_string()).into());
+        };
+
+        let uploads_dir = &self.config.uploads_dir;
+        check("uploads_dir", config::check_upload_dir(uploads_dir)
+              .map(drop)
+              .map_err(|e| e.to_string()));
+
+        let min_free_space = self.config.min_free_space;
+        check("free_space", free_space(path::Path::new(uploads_dir))
+              .map_err(|e| e.to_string())
+              .and_then(|free| if free >= min_free_space {
+                  Ok(())
+              } else {
+                  Err(format!("{} bytes free, {} required", free, min_free_space))
+              }));
+
+        json_response(if ready { 200 } else { 503 },
+                      &serde_json::json!({ "ready": ready, "checks": checks }))
+    }
+
+    fn handle_metrics(&self)
+        -> Result<tiny_http::Response<Cursor<Vec<u8>>>, Error>
+    {
+        let free_space = match free_space(
+            path::Path::new(&self.config.uploads_dir))
+        {
+            Ok(free_space) => Some(free_space),
+            Err(e) => {
+                println!("Could not get free space in {}: {}",
+                         self.config.uploads_dir, e);
+                None
+            }
+        };
+
+        Ok(tiny_http::Response::from_data(self.metrics.render(free_space))
+           .with_header(content_type_header(
+               "text/plain; version=0.0.4; charset=utf-8")))
+    }
+
+    fn handle_file_list(&self) ->
+        Result<tiny_http::Response<Cursor<Cow<'_, [u8]>>>, Error>
+    {
+        const FILES_FILENAME: &str = "files.html";
+        let content = StaticAsset::get(FILES_FILENAME)
+            .ok_or_else(|| Error::new(
+                ErrorKind::ServerError,
+                format!("Files: {} not found", FILES_FILENAME)))?;
+
+        let files = self.stored_files()
+            .map_err(|e| Error::from_io_error(e, "list uploads"))?;
+
+        let mut rows = String::new();
+        for file in files.iter() {
+            rows.push_str(&format!(
+                r#"
+      <tr>
+        <td>{date}</td>
+        <td><a href="/files/{filename}">{name}</a></td>
+        <td>{typ}</td>
+        <td>{size}</td>
+      </tr>"#,
+                date = file.timestamp.format("%F %T"),
+                filename = escape_html(&file.filename),
+                name = escape_html(file.name.as_deref().unwrap_or("(no name)")),
+                typ = file.typ,
+                size = file.size,
+            ));
+        }
+
+        let content = unsafe {
+            search_and_replace(
+                content.into_owned(), b"#{files}", rows.as_bytes())
+        };
+        let cur = Cursor::new(Cow::from(content));
+
+        Ok(tiny_http::Response::new(
+            tiny_http::StatusCode(200),
+            vec![self.html_content_type.clone()],
+            cur,
+            None,
+            None,
+        ))
+    }
+
+    /// Streams back a payload listed by `handle_file_list`
+    fn handle_file_download(&self, filename: &str) ->
+        Result<tiny_http::Response<fs::File>, Error>
+    {
+        let filename = percent_decode(filename.as_bytes())
+            .decode_utf8()
+            .map_err(|_| Error::new(
+                ErrorKind::UserError, "File name is not valid UTF-8"))?;
+        let not_found = || Error::new(
+            ErrorKind::NotFound, format!("File \"{}\" not found", filename));
+
+        if filename.contains('/') || filename.contains('\\') {
+            return Err(not_found());
+        }
+        let (name, typ) = match parse_mangled_filename(&filename) {
+            Some(m) if m.file_type == FileType::Payload => (m.name, m.typ),
+            _ => match self.templated_payload(&filename) {
+                Some((_, typ)) => (Some(&*filename), typ),
+                None => return Err(not_found()),
+            },
+        };
+
+        let path = path::Path::new(&self.config.uploads_dir).join(&*filename);
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(not_found());
+            }
+            Err(e) => return Err(Error::from_io_error(e, "open upload")),
+        };
+        let metadata = file.metadata()
+            .map_err(|e| Error::from_io_error(e, "open upload"))?;
+        if !metadata.is_file() {
+            return Err(not_found());
+        }
+
+        let (content_type, disposition) = match typ {
+            UploadType::Text => ("text/plain; charset=utf-8", "inline"),
+            UploadType::File => {
+                let mut head = Vec::with_capacity(mime::SNIFF_LEN);
+                (&file).take(mime::SNIFF_LEN as u64).read_to_end(&mut head)
+                    .and_then(|_| (&file).rewind())
+                    .map_err(|e| Error::from_io_error(e, "read upload"))?;
+                (mime::content_type(name.unwrap_or(""), &head),
+                 "attachment")
+            }
+        };
+        let disposition = content_disposition(
+            disposition, name.unwrap_or(&filename));
+        let disposition = tiny_http::Header::from_bytes(
+            &b"Content-Disposition"[..], disposition)
+            .map_err(|()| Error::new(
+                ErrorKind::ServerError, "invalid Content-Disposition"))?;
+
+        Ok(tiny_http::Response::new(
+            tiny_http::StatusCode(200),
+            vec![content_type_header(content_type), disposition,
+                 // browsers shouldn't second-guess what we've said
+                 header("X-Content-Type-Options", "nosniff")],
+            file,
+            Some(metadata.len() as usize),
+            None,
+        ))
+    }
+
+    /// Called once the payload is in place: writes its metadata,
+    /// runs the --on-upload hook and sends the --webhook event
+    fn payload_stored(&self, payload: &StoredPayload, req: &RequestInfo)
+                      -> Result<(), Error>
+    {
+        self.write_metadata(payload, req)?;
+        self.metrics.payload_stored(payload.typ.name(), payload.size);
+
+        STORED_FILENAMES.with(|stored| {
+            stored.borrow_mut().push(payload.stored_filename.to_string());
+        });
+
+        if let Some(hooks) = &self.hooks {
+            let dir = path::Path::new(&self.config.uploads_dir);
+            let metadata_path = if self.config.save_metadata {
+                Some(dir.join(self.metadata_filename(payload.stored_filename)))
+            } else {
+                None
+            };
+
+            hooks.notify(hooks::UploadEvent {
+                path: dir.join(payload.stored_filename),
+                original_name: payload.original_name.map(str::to_string),
+                typ: payload.typ.name(),
+                size: payload.size,
+                sha256: payload.sha256.clone(),
+                metadata_path,
+            });
+        }
+
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(serde_json::json!({
+                "event": "upload",
+                "stored_filename": payload.stored_filename,
+                "original_name": payload.original_name,
+                "type": payload.typ.name(),
+                "size": payload.size,
+                "sha256": payload.sha256,
+                "client_ip": req.remote_addr.ip().to_string(),
+                "timestamp": payload.now.to_rfc3339(),
+            }));
+        }
+        Ok(())
+    }
+
+    fn write_metadata(&self, payload: &StoredPayload, req: &RequestInfo)
+                      -> Result<(), Error>
     {
         if !self.config.save_metadata {
             return Ok(())
         }
 
-        let mut meta_file = self.create_file(
-            now, upload_type, FileType::Metadata, name)
+        let path = path::Path::new(&self.config.uploads_dir)
+            .join(self.metadata_filename(payload.stored_filename));
+        let mut meta_file = self.create_file(&path)
             .map_err(|e| Error::from_io_error(e, "create metadata file error"))?;
+        let staging_path = self.staging_path(&path);
 
-        meta_file.write_fmt(
-            format_args!("{} {}\n\n", req.method(), req.http_version()))
-            .map_err(|e| Error::from_io_error(e, "write metadata"))?;
+        let written = match self.config.meta_format {
+            config::MetaFormat::Text => {
+                let mut text = format!("{} {}\n\n", req.method, req.http_version);
+                for h in req.public_headers() {
+                    text.push_str(&format!("{}: {}\n", h.field, h.value));
+                }
+                meta_file.write_all(text.as_bytes())
+            }
+            config::MetaFormat::Json => {
+                serde_json::to_writer_pretty(&mut meta_file, &payload.to_json(req))
+                    .map_err(io::Error::from)
+                    .and_then(|()| meta_file.write_all(b"\n"))
+            }
+        };
 
-        for h in req.headers().iter() {
-            meta_file.write_fmt(format_args!("{}: {}\n", h.field, h.value))
-                .map_err(|e| Error::from_io_error(e, "write metadata"))?;
-        }
+        let size = written
+            .and_then(|()| meta_file.sync_all())
+            .and_then(|()| meta_file.metadata());
+        let size = match size {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                self.remove_staged(&staging_path);
+                return Err(Error::from_io_error(e, "write metadata"));
+            }
+        };
+        self.publish(&staging_path, &path)?;
+        self.quota.stored(size);
         Ok(())
     }
 
-    fn save_text(&self, req: &mut tiny_http::Request) -> Result<String, Error> {
+    /// Returns the received text and what we've saved
+    fn save_text(&self, req: &mut tiny_http::Request)
+                 -> Result<(String, SavedFile), Error> {
         if req.method() != &tiny_http::Method::Post {
             return Err(Error::new(
                 ErrorKind::UserError, "Send POST to this path"));
         }
 
+        let limit = self.upload_limit(self.config.max_text_size)?;
+        // url encoding takes up to 3 bytes for every byte of the text
+        let body_limit = limit.map(
+            |limit| limit.saturating_mul(3).saturating_add("text=".len() as u64));
+
+        if let (Some(body_limit), Some(body_length)) =
+            (body_limit, req.body_length())
+        {
+            if body_length as u64 > body_limit {
+                return Err(too_large(limit.unwrap()));
+            }
+        }
+
         let mut data = Vec::new();
 
-        req.as_reader()
+        // read one byte past the limit to find out whether it's exceeded
+        Read::take(body_reader(req),
+                   body_limit.map_or(u64::MAX, |l| l.saturating_add(1)))
             .read_to_end(&mut data)
             .map_err(|e| Error::from_io_error(e, "Error receiving the data"))?;
+        if let Some(body_limit) = body_limit {
+            if data.len() as u64 > body_limit {
+                return Err(too_large(limit.unwrap()));
+            }
+        }
 
         let mut parser = form_urlencoded::parse(data.as_slice());
         let (k, v) = match parser.next() {
@@ -327,89 +1488,257 @@ impl<'config> Srv<'config> {
         }
 
         let mut text = v;
+        if let Some(limit) = limit {
+            if text.len() as u64 > limit {
+                return Err(too_large(limit));
+            }
+        }
 
-        let now: chrono::DateTime<chrono::Local> =
-            chrono::offset::Local::now();
-        self.write_metadata::<&str>(now, UploadType::Text, None, req)?;
-
-        self.write_text(now, text.to_mut())
-            .map_err(|e| Error::from_io_error(e, "Write error"))?;
+        let reservation = self.quota.reserve();
+        if !reservation.grow(text.len() as u64) {
+            return Err(quota_exhausted());
+        }
 
-        Ok(format!("Saved text: {}", text))
+        let req_info = RequestInfo::new(req);
+        let name = PayloadName {
+            now: chrono::offset::Local::now(),
+            typ: UploadType::Text,
+            name: None,
+            client_ip: req_info.remote_addr.ip(),
+        };
+        let saved = self.write_text(&name, text.to_mut(), &reservation)?;
+
+        self.payload_stored(&StoredPayload {
+            now: name.now,
+            typ: UploadType::Text,
+            stored_filename: &saved.stored_filename,
+            original_name: None,
+            content_type: Some("text/plain; charset=utf-8"),
+            size: saved.size,
+            sha256: saved.sha256.clone(),
+        }, &req_info)?;
+
+        Ok((text.into_owned(), saved))
     }
 
-    fn handle_text(&self,  req: &mut tiny_http::Request)
-                   -> Result<tiny_http::Response<Cursor<Cow<[u8]>>>, Error> {
+    fn handle_text(&self,  req: &mut tiny_http::Request, api: bool)
+                   -> Result<tiny_http::Response<Cursor<Cow<'_, [u8]>>>, Error> {
         match self.save_text(req) {
-            Ok(msg) => Err(Error::new(ErrorKind::Success, msg)),
+            Ok((_, saved)) if api => {
+                let mut json = saved.to_json();
+                json["status"] = ErrorKind::Success.as_http_code().into();
+                Ok(json_response(ErrorKind::Success.as_http_code(), &json))
+            }
+            Ok((text, saved)) => {
+                Err(Error::new(
+                    ErrorKind::Success,
+                    format!("Saved text: {}<br/>sha256 {}{}",
+                            text, saved.sha256,
+                            if saved.duplicate { " (sent before)" } else { "" })))
+            }
             Err(err) => {
-                Err(Error::new(ErrorKind::ServerError,
-                               format!("save text error: {:?}", err)))
+                Err(Error::new(err.kind,
+                               format!("save text error: {}", err.msg)))
             }
         }
     }
 
-    /// Saves the uploaded file
-    fn save_file_from_request(&self, req: &mut tiny_http::Request)
-                              -> Result<(), Error> {
-        let now: chrono::DateTime<chrono::Local> =
-            chrono::offset::Local::now();
-        self.write_metadata(now, UploadType::File, Some("upload"), req)?;
+    /// Saves one file from a multipart request
+    fn save_multipart_file<M: ReadEntry>(
+        &self, data: &mut MultipartData<M>, headers: &FieldHeaders,
+        req: &RequestInfo, reservation: &quota::Reservation)
+        -> Result<SavedFile, Error>
+    {
+        let name = headers.filename.as_deref().map(|name| self.sanitize_filename(name));
+        let name = name.as_deref();
+
+        let limit = self.upload_limit(self.config.max_file_size)?;
+        let (now, file) = self
+            .create_unique_file(UploadType::File, FileType::Payload, name)
+            .map_err(|e| Error::from_io_error(e, "create file error"))?;
+        let path = self.stored_path(
+            now, UploadType::File, FileType::Payload, name);
+        let staging_path = self.staging_path(&path);
+        let _incomplete = self.mark_incomplete(&staging_path);
+
+        let mut file = hash::Sha256Writer::new(file);
+        let result = data
+            .save()
+            .memory_threshold(64 * 1024 * 1024)
+            .size_limit(limit.unwrap_or(u64::MAX))
+            .write_to(&mut file);
+
+        let err = match result {
+            SaveResult::Full(_) => None,
+            _ if reservation.exceeded() => Some(quota_exhausted()),
+            SaveResult::Partial(_, PartialReason::SizeLimit) => {
+                Some(too_large(limit.unwrap_or(u64::MAX)))
+            }
+            SaveResult::Partial(partial, partial_reason) => {
+                Some(Error::new(
+                    ErrorKind::Unknown,
+                    format!(
+                        "data partially saved/received, partial = {}, \
+                         partial_reason = {:?}", partial, partial_reason),
+                ))
+            }
+            SaveResult::Error(error) => {
+                Some(Error::new(
+                    ErrorKind::ServerError,
+                    format!("data save error: {}", error),
+                ))
+            }
+        };
 
-        let mut req = Multipart::from_request(req)
-            .map_err(|e| Error::new(ErrorKind::ServerError,
-                                    format!("{:?}", e)))?;
+        if let Some(err) = err {
+            // don't leave incomplete files around
+            self.remove_staged(&staging_path);
+            return Err(err);
+        }
 
-        let mut err: Result<(), Error> =
-            Err(Error::new(ErrorKind::UserError, "no entries provided"));
-        req.foreach_entry(|mut entry| {
-            let name = &*entry.headers.name.clone();
-            if name == "file" {
-                let file = self
-                    .create_file(
-                        now,
-                        UploadType::File,
-                        FileType::Payload,
-                        entry.headers.filename.map(sanitize_filename),
-                    ).map_err(|e| Error::from_io_error(e, "create file error"));
-
-                let file = match file {
-                    Ok(file) => file,
-                    Err(e) => {
-                        err = Err(e);
-                        return;
-                    }
-                };
+        let saved = self.store_payload(file, &staging_path, &PayloadName {
+            now,
+            typ: UploadType::File,
+            name,
+            client_ip: req.remote_addr.ip(),
+        }, reservation)?;
+
+        let content_type = headers.content_type
+            .as_ref()
+            .map(|mime| mime.to_string());
+        self.payload_stored(&StoredPayload {
+            now,
+            typ: UploadType::File,
+            stored_filename: &saved.stored_filename,
+            original_name: headers.filename.as_deref(),
+            content_type: content_type.as_deref(),
+            size: saved.size,
+            sha256: saved.sha256.clone(),
+        }, req)?;
+
+        Ok(saved)
+    }
 
-                let result = entry
-                    .data
-                    .save()
-                    .memory_threshold(64 * 1024 * 1024)
-                    .write_to(file);
-
-                match result {
-                    SaveResult::Full(_) => {}
-                    SaveResult::Partial(partial, partial_reason) => {
-                        err = Err(Error::new(
-                            ErrorKind::Unknown,
-                            format!(
-                                "data partially saved/received, partial = {}, \
-                                 partial_reason = {:?}", partial, partial_reason),
-                        ))
-                    }
-                    SaveResult::Error(error) => {
-                        err = Err(Error::new(
-                            ErrorKind::ServerError,
-                            format!("data save error: {}", error),
-                        ));
-                    }
-                }
-            } else {
-                err = Err(Error::new(
-                    ErrorKind::UserError,
-                    format!("invalid entry (expected only \"file\") {}", name),
-                ));
+    /// Saves the request body as is, for clients like `curl -T`
+    fn save_raw_body(&self, req: &mut tiny_http::Request,
+                     original_name: Option<&str>,
+                     progress: Option<&progress::Progress>)
+                     -> Result<SavedFile, Error> {
+        let limit = self.upload_limit(self.config.max_file_size)?;
+        if let (Some(limit), Some(body_length)) = (limit, req.body_length()) {
+            if body_length as u64 > limit {
+                return Err(too_large(limit));
+            }
+        }
+
+        let req_info = RequestInfo::new(req);
+        let name = original_name.map(|name| self.sanitize_filename(name));
+        let name = name.as_deref();
+
+        let (now, file) = self
+            .create_unique_file(UploadType::File, FileType::Payload, name)
+            .map_err(|e| Error::from_io_error(e, "create file error"))?;
+        let path = self.stored_path(
+            now, UploadType::File, FileType::Payload, name);
+        let staging_path = self.staging_path(&path);
+        let _incomplete = self.mark_incomplete(&staging_path);
+
+        // read one byte past the limit to find out whether it's exceeded
+        let reservation = self.quota.reserve();
+        let mut file = hash::Sha256Writer::new(file);
+        let copied = io::copy(
+            &mut Read::take(
+                quota::QuotaReader::new(
+                    progress::ProgressReader::new(body_reader(req), progress),
+                    &reservation),
+                limit.map_or(u64::MAX, |l| l.saturating_add(1))),
+            &mut file);
+
+        let err = match (copied, limit) {
+            (Err(_), _) if reservation.exceeded() => Some(quota_exhausted()),
+            (Ok(size), Some(limit)) if size > limit => Some(too_large(limit)),
+            (Ok(_), _) => None,
+            (Err(e), _) => Some(Error::from_io_error(e, "receive file")),
+        };
+        if let Some(err) = err {
+            // don't leave incomplete files around
+            self.remove_staged(&staging_path);
+            return Err(err);
+        }
+
+        let saved = self.store_payload(file, &staging_path, &PayloadName {
+            now,
+            typ: UploadType::File,
+            name,
+            client_ip: req_info.remote_addr.ip(),
+        }, &reservation)?;
+
+        self.payload_stored(&StoredPayload {
+            now,
+            typ: UploadType::File,
+            stored_filename: &saved.stored_filename,
+            original_name,
+            content_type: header_value(req, "Content-Type"),
+            size: saved.size,
+            sha256: saved.sha256.clone(),
+        }, &req_info)?;
+
+        Ok(saved)
+    }
+
+    /// Saves every file uploaded in the request
+    fn save_file_from_request(&self, req: &mut tiny_http::Request,
+                              progress: Option<&progress::Progress>)
+                              -> Result<Vec<FileResult>, Error> {
+        if let (Some(remaining), Some(body_length)) =
+            (self.remaining_quota()?, req.body_length())
+        {
+            if body_length as u64 > remaining {
+                return Err(too_large(remaining));
+            }
+        }
+
+        let req_info = RequestInfo::new(req);
+        let boundary = multipart_boundary(req)
+            .ok_or_else(|| Error::new(ErrorKind::UserError,
+                                      "No boundary in Content-Type"))?
+            .to_string();
+        let reservation = self.quota.reserve();
+        let mut req = Multipart::with_body(
+            quota::QuotaReader::new(
+                progress::ProgressReader::new(body_reader(req), progress),
+                &reservation),
+            boundary);
+
+        let mut results: Vec<FileResult> = Vec::new();
+        req.foreach_entry(|mut entry| {
+            let field = entry.headers.name.to_string();
+            if field != "file" {
+                results.push(FileResult {
+                    result: Err(Error::new(
+                        ErrorKind::UserError,
+                        format!("invalid entry (expected only \"file\") {}",
+                                field),
+                    )),
+                    name: field,
+                });
+                return;
             }
+
+            // browsers send an empty file without a name
+            // when nothing is selected
+            let name = match entry.headers.filename.as_deref() {
+                Some("") => return,
+                Some(filename) => Some(self.sanitize_filename(filename)),
+                None => None,
+            };
+
+            let result = self.save_multipart_file(
+                &mut entry.data, &entry.headers, &req_info, &reservation);
+            results.push(FileResult {
+                name: name.unwrap_or_else(|| "(no name)".to_string()),
+                result,
+            });
         }).map_err(|e| {
             Error::new(
                 ErrorKind::ServerError,
@@ -417,37 +1746,355 @@ impl<'config> Srv<'config> {
             )
         })?;
 
-        Ok(())
+        if results.is_empty() {
+            return Err(Error::new(ErrorKind::UserError, "no files provided"));
+        }
+
+        Ok(results)
     }
 
-    fn handle_file_upload(&self, req: &mut tiny_http::Request) ->
-        Result<tiny_http::Response<Cursor<Cow<[u8]>>>, Error> {
-            match self.save_file_from_request(req) {
-                Ok(()) => {
-                    Err(Error::new(ErrorKind::Success, "File uploaded!"))
+    fn sanitize_filename(&self, filename: &str) -> String {
+        sanitize_filename_with_mode(filename, self.config.filename_mode)
+    }
+
+    /// Id the client wants to look the upload progress up by,
+    /// from the X-Upload-Id header or the upload_id query parameter
+    fn upload_id(&self, req: &tiny_http::Request) -> Result<Option<String>, Error> {
+        let id = match header_value(req, "X-Upload-Id") {
+            Some(id) => Some(id.to_string()),
+            None => self.base_url.join(req.url()).ok().and_then(|url| {
+                url.query_pairs()
+                    .find(|(key, _)| key == "upload_id")
+                    .map(|(_, id)| id.into_owned())
+            }),
+        };
+
+        match id {
+            Some(id) if !progress::is_valid_id(&id) => Err(Error::new(
+                ErrorKind::UserError,
+                "Upload id may only have letters, digits, '-' and '_'")),
+            id => Ok(id),
+        }
+    }
+
+    /// Multipart form uploads and raw bodies sent with POST or PUT.
+    /// `path_name` is the file name from /file/<name>.
+    fn handle_file_upload(&self, req: &mut tiny_http::Request,
+                          path_name: Option<&str>, api: bool) ->
+        Result<tiny_http::Response<Cursor<Cow<'_, [u8]>>>, Error> {
+            let method = req.method();
+            if method != &tiny_http::Method::Post &&
+                method != &tiny_http::Method::Put
+            {
+                return Err(Error::new(
+                    ErrorKind::UserError, "Send POST or PUT to this path"));
+            }
+
+            let progress = self.upload_id(req)?.map(|id| {
+                self.progress.start(&id, req.body_length().map(|l| l as u64))
+            });
+            let progress = progress.as_ref().map(progress::ProgressGuard::progress);
+
+            let is_multipart = header_value(req, "Content-Type")
+                .is_some_and(|t| t.starts_with("multipart/form-data"));
+            let results = if is_multipart && path_name.is_none() {
+                self.save_file_from_request(req, progress)?
+            } else {
+                let path_name = path_name.map(|name| {
+                    percent_decode(name.as_bytes())
+                        .decode_utf8_lossy()
+                        .into_owned()
+                });
+                let name = path_name.or_else(|| {
+                    header_value(req, "Content-Disposition")
+                        .and_then(content_disposition_filename)
+                });
+
+                let result = self.save_raw_body(req, name.as_deref(), progress);
+                vec![FileResult {
+                    name: name.unwrap_or_else(|| "(no name)".to_string()),
+                    result,
+                }]
+            };
+
+            // report the first failure if there is one
+            let kind = results.iter()
+                .find_map(|r| r.result.as_ref().err().map(|e| e.kind))
+                .unwrap_or(ErrorKind::Success);
+
+            if api {
+                let files: Vec<serde_json::Value> =
+                    results.iter().map(FileResult::to_json).collect();
+                return Ok(json_response(
+                    kind.as_http_code(),
+                    &serde_json::json!({
+                        "status": kind.as_http_code(),
+                        "files": files,
+                    })));
+            }
+            let mut msg = match (kind, results.len()) {
+                (ErrorKind::Success, 1) => "File uploaded!".to_string(),
+                (ErrorKind::Success, _) => "Files uploaded!".to_string(),
+                _ => "Not everything was uploaded:".to_string(),
+            };
+
+            for file in results.iter() {
+                let status = match &file.result {
+                    Ok(saved) => format!(
+                        "{} bytes, sha256 {}{}", saved.size, saved.sha256,
+                        if saved.duplicate { " (uploaded before)" } else { "" }),
+                    Err(err) => escape_html(&err.msg),
+                };
+                msg.push_str(&format!(
+                    "<br/>{}: {}", escape_html(&file.name), status));
+            }
+
+            Err(Error::new(kind, msg))
+        }
+
+    /// Moves a completed resumable upload next to the other payloads
+    fn finish_tus_upload(&self, upload: &tus::Upload, req: &tiny_http::Request)
+                         -> Result<(), Error> {
+        let now: chrono::DateTime<chrono::Local> =
+            chrono::offset::Local::now();
+        let name = upload.info.filename.as_deref();
+        let path = self.stored_path(now, UploadType::File, FileType::Payload, name);
+        let data_path = self.tus.data_path(&upload.id);
+
+        let (size, sha256) = hash::sha256_file(&data_path)
+            .map_err(|e| Error::from_io_error(e, "hash completed upload"))?;
+
+        let staging_path = self.staging_path(&path);
+        fs::File::open(&data_path)
+            .and_then(|file| file.sync_all())
+            .and_then(|()| fs::create_dir_all(staging_path.parent().unwrap()))
+            .and_then(|()| fs::hard_link(&data_path, &staging_path))
+            .map_err(|e| Error::from_io_error(e, "store completed upload"))?;
+        let duplicate = match self.dedup_payload(&staging_path, &sha256) {
+            Ok(duplicate) => duplicate,
+            Err(err) => {
+                self.remove_staged(&staging_path);
+                return Err(err);
+            }
+        };
+        let req_info = RequestInfo::new(req);
+        let name = PayloadName {
+            now,
+            typ: UploadType::File,
+            name,
+            client_ip: req_info.remote_addr.ip(),
+        };
+        let stored_filename = self.publish_payload(
+            &staging_path, &name, self.payload_filename(&name, &sha256))?;
+
+        // until the payload is stored the client can resume with
+        // an empty PATCH and have it stored again. The data has been
+        // counted against the quota as it's been received, a duplicate
+        // gives it back.
+        match self.tus.remove(&upload.id) {
+            Ok(()) if duplicate => self.quota.freed(size),
+            Ok(()) => {}
+            Err(err) => println!("Could not remove completed upload {}: {}",
+                                 upload.id, err.msg),
+        }
+
+        self.payload_stored(&StoredPayload {
+            now,
+            typ: UploadType::File,
+            stored_filename: &stored_filename,
+            original_name: upload.info.original_filename.as_deref(),
+            content_type: upload.info.content_type.as_deref(),
+            size,
+            sha256,
+        }, &req_info)
+    }
+
+    /// Resumable uploads using the tus protocol, see tus.rs
+    fn handle_tus(&self, req: &mut tiny_http::Request, id: Option<&str>)
+                  -> Result<tiny_http::ResponseBox, Error>
+    {
+        use tiny_http::Method;
+
+        let method = req.method().clone();
+        if method != Method::Options &&
+            header_value(req, "Tus-Resumable") != Some(tus::TUS_VERSION)
+        {
+            return Err(Error::new(
+                ErrorKind::PreconditionFailed,
+                format!("Only tus {} is supported", tus::TUS_VERSION)));
+        }
+
+        let no_content = tiny_http::Response::empty(204);
+
+        match (method, id) {
+            (Method::Options, _) => {
+                let mut resp = no_content
+                    .with_header(header("Tus-Version", tus::TUS_VERSION))
+                    .with_header(header("Tus-Extension", tus::TUS_EXTENSIONS));
+                if let Some(max_size) = self.config.max_file_size {
+                    resp.add_header(header("Tus-Max-Size", max_size));
+                }
+                Ok(resp.boxed())
+            }
+            (Method::Post, None) => {
+                if header_value(req, "Upload-Defer-Length").is_some() {
+                    return Err(Error::new(
+                        ErrorKind::UserError,
+                        "Upload-Defer-Length is not supported"));
+                }
+                let length: u64 = header_value(req, "Upload-Length")
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| Error::new(
+                        ErrorKind::UserError,
+                        "Missing or invalid Upload-Length"))?;
+                if let Some(limit) = self.upload_limit(self.config.max_file_size)? {
+                    if length > limit {
+                        return Err(too_large(limit));
+                    }
                 }
-                Err(err) => {
-                    Err(err)
+                let metadata = match header_value(req, "Upload-Metadata") {
+                    Some(value) => tus::parse_metadata(value)?,
+                    None => Default::default(),
+                };
+                let original_filename = metadata.get("filename")
+                    .map(|name| String::from_utf8_lossy(name).into_owned());
+                let info = tus::FileInfo {
+                    filename: original_filename.as_deref()
+                        .map(|name| self.sanitize_filename(name)),
+                    original_filename,
+                    content_type: metadata.get("filetype")
+                        .map(|t| String::from_utf8_lossy(t).into_owned()),
+                };
+
+                let upload = self.tus.create(length, info)
+                    .map_err(|e| Error::from_io_error(e, "create upload"))?;
+                if upload.is_complete() {
+                    self.finish_tus_upload(&upload, req)?;
+                }
+
+                Ok(tiny_http::Response::empty(201)
+                   .with_header(header("Location",
+                                       format!("/tus/{}", upload.id)))
+                   .boxed())
+            }
+            (Method::Head, Some(id)) => {
+                let upload = self.tus.get(id)?;
+                Ok(tiny_http::Response::empty(200)
+                   .with_header(header("Upload-Offset", upload.offset))
+                   .with_header(header("Upload-Length", upload.length))
+                   .with_header(header("Cache-Control", "no-store"))
+                   .boxed())
+            }
+            (Method::Patch, Some(id)) => {
+                if header_value(req, "Content-Type") !=
+                    Some("application/offset+octet-stream")
+                {
+                    return Err(Error::new(
+                        ErrorKind::UnsupportedMediaType,
+                        "Content-Type must be application/offset+octet-stream"));
                 }
+                let offset: u64 = header_value(req, "Upload-Offset")
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| Error::new(
+                        ErrorKind::UserError,
+                        "Missing or invalid Upload-Offset"))?;
+
+                // what's received is kept even if the request fails
+                let reservation = self.quota.reserve();
+                let appended = self.tus.append(
+                    id, offset,
+                    &mut quota::QuotaReader::new(body_reader(req), &reservation));
+                reservation.commit(reservation.size());
+                let upload = match appended {
+                    Err(_) if reservation.exceeded() => return Err(quota_exhausted()),
+                    appended => appended?,
+                };
+                if upload.is_complete() {
+                    self.finish_tus_upload(&upload, req)?;
+                }
+
+                Ok(no_content
+                   .with_header(header("Upload-Offset", upload.offset))
+                   .boxed())
+            }
+            (Method::Delete, Some(id)) => {
+                let upload = self.tus.get(id)?;
+                self.tus.remove(id)?;
+                self.quota.freed(upload.offset);
+                Ok(no_content.boxed())
+            }
+            (method, _) => {
+                Err(Error::new(
+                    ErrorKind::UserError,
+                    format!("{} is not supported here", method)))
             }
         }
+    }
 
-    fn respond(&self, start_t: time::Instant,
-               req: tiny_http::Request,
-               resp_result: Result<tiny_http::Response<Cursor<Cow<[u8]>>>, Error>) {
+    fn respond<R: io::Read>(&self, start_t: time::Instant,
+                            req: tiny_http::Request,
+                            resp_result: Result<tiny_http::Response<R>, Error>) {
 
         let method = req.method().clone();
         let url = req.url().to_string();
-
-        let resp: tiny_http::Response<Cursor<Cow<[u8]>>> = match resp_result {
-            Ok(resp) => resp,
-            Err(err) => self.error_response(&err),
+        // when the request has arrived
+        let time = chrono::offset::Local::now() -
+            chrono::Duration::from_std(start_t.elapsed())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        let req_info = RequestInfo::new(&req);
+        let bytes_received = BYTES_RECEIVED.with(|received| received.take());
+
+        // responses with different bodies have different types,
+        // so each one is sent separately
+        let (make_resp_dur, (respond_result, writer)) = match resp_result {
+            Ok(resp) => (start_t.elapsed(), send_response(req, resp)),
+            Err(err) => {
+                let resp = self.error_response(&err, is_api_request(&req));
+                (start_t.elapsed(), send_response(req, resp))
+            }
         };
-
-        let make_resp_dur = start_t.elapsed();
-        let respond_result = req.respond(resp);
         let resp_complete_dur = start_t.elapsed();
 
+        let route = route_name(&self.base_url, &url);
+        if let Some(status) = writer.status() {
+            self.metrics.request_handled(route, status);
+        }
+        if is_upload_route(route) {
+            self.metrics.upload_finished(route, resp_complete_dur);
+        }
+
+        let stored_filenames = STORED_FILENAMES.with(|stored| stored.take());
+
+        if let Some(access_log) = &self.config.access_log {
+            let header = |field| {
+                req_info.headers.iter()
+                    .find(|h| h.field.equiv(field))
+                    .map(|h| h.value.as_str())
+            };
+            let user = header("Authorization").and_then(auth::basic_user);
+
+            access_log.log(&access_log::Entry {
+                remote_addr: req_info.remote_addr,
+                user: user.as_deref(),
+                time,
+                method: method.as_str(),
+                url: &url,
+                http_version: &req_info.http_version.to_string(),
+                status: writer.status(),
+                bytes_received,
+                bytes_sent: writer.bytes(),
+                referer: header("Referer"),
+                user_agent: header("User-Agent"),
+                stored_filenames: &stored_filenames,
+                duration: resp_complete_dur,
+            });
+
+            if let Err(err) = respond_result {
+                println!("{} {} => {:?}", method.as_str(), url, err);
+            }
+            return;
+        }
+
         match respond_result {
             Ok(()) => {
                 println!(
@@ -463,11 +2110,42 @@ impl<'config> Srv<'config> {
         }
     }
 
-    fn handle_request(&self, base_url: &Url, mut req: tiny_http::Request) {
-        let start_t = time::Instant::now();
+    fn respond_error(&self, start_t: time::Instant,
+                     req: tiny_http::Request, err: Error) {
+        self.respond::<io::Empty>(start_t, req, Err(err));
+    }
 
+    /// `start_t` is when the request has been accepted
+    fn handle_request(&self, base_url: &Url, start_t: time::Instant,
+                      mut req: tiny_http::Request) {
         self.die_if_single_request();
 
+        let route = route_name(base_url, req.url());
+        let _in_flight = is_upload_route(route)
+            .then(|| self.metrics.upload_started());
+
+        // assets are the same for everyone, no need to protect them,
+        // and probes can't log in.
+        // Look at the normalized path so /assets/../file isn't an asset
+        if let Some(auth) = &self.config.auth {
+            let is_public = base_url.join(req.url()).ok()
+                .and_then(|url| url.path_segments()
+                          .and_then(|mut s| s.next().map(|s| {
+                              matches!(s, "assets" | "healthz" | "readyz")
+                          })))
+                .unwrap_or(false);
+
+            if !is_public &&
+                !auth.is_authorized(header_value(&req, "Authorization"))
+            {
+                self.respond_error(
+                    start_t, req,
+                    Error::new(ErrorKind::Unauthorized,
+                               "Log in to use this server"));
+                return;
+            }
+        }
+
         let url = req.url();
 
         if url == "/" {
@@ -477,32 +2155,80 @@ impl<'config> Srv<'config> {
 
         let url = base_url.join(url).unwrap();
         let mut path_segments = url.path_segments().unwrap();
+        let api = is_api_request(&req);
+        if url.path().starts_with(API_PREFIX) {
+            // skip "api" and "v1", the rest of the routes are the same
+            path_segments.nth(1);
+        }
 
         match path_segments.next() {
             Some("assets") => {
                 if let Some(filename) = path_segments.next() {
                     self.respond(start_t, req, self.handle_static_asset(filename));
                 } else {
-                    self.respond(start_t, req, Err(
+                    self.respond_error(start_t, req,
                         Error::new(ErrorKind::NotFound, "/assets is not enumeratable")
-                    ));
+                    );
                 }
             }
             Some("text") => {
-                let resp = self.handle_text(&mut req);
+                let resp = self.handle_text(&mut req, api);
                 self.respond(start_t, req, resp);
             }
             Some("file") => {
-                let resp = self.handle_file_upload(&mut req);
+                let name = path_segments.next().filter(|name| !name.is_empty());
+                let resp = self.handle_file_upload(&mut req, name, api);
+                self.respond(start_t, req, resp);
+            }
+            Some("tus") => {
+                let id = path_segments.next().filter(|id| !id.is_empty());
+                let resp = match self.handle_tus(&mut req, id) {
+                    Ok(resp) => resp,
+                    Err(err) => {
+                        self.error_response(&err, is_api_request(&req)).boxed()
+                    }
+                };
+                let resp = resp
+                    .with_header(header("Tus-Resumable", tus::TUS_VERSION));
+                self.respond::<Box<dyn io::Read + Send>>(start_t, req, Ok(resp));
+            }
+            Some("files") if self.config.list_uploads => {
+                match path_segments.next() {
+                    None | Some("") => {
+                        self.respond(start_t, req, self.handle_file_list());
+                    }
+                    Some(filename) => {
+                        self.respond(
+                            start_t, req, self.handle_file_download(filename));
+                    }
+                }
+            }
+            Some("metrics") => {
+                self.respond(start_t, req, self.handle_metrics());
+            }
+            Some("progress") => {
+                let resp = path_segments.next()
+                    .filter(|id| progress::is_valid_id(id))
+                    .and_then(|id| self.progress.get(id))
+                    .map(|json| json_response(200, &json))
+                    .ok_or_else(|| Error::new(
+                        ErrorKind::NotFound, "No upload with this id"));
                 self.respond(start_t, req, resp);
             }
+            Some("healthz") => {
+                self.respond(start_t, req, Ok(json_response(
+                    200, &serde_json::json!({ "status": "ok" }))));
+            }
+            Some("readyz") => {
+                self.respond(start_t, req, Ok(self.handle_readyz()));
+            }
             Some(other) => {
-                self.respond(
+                self.respond_error(
                     start_t, req,
-                    Err(Error::new(
+                    Error::new(
                         ErrorKind::NotFound,
                         format!("There's nothing at /{}", other),
-                    )));
+                    ));
             }
             None => {
                 unreachable!(
@@ -513,17 +2239,477 @@ impl<'config> Srv<'config> {
         }
     }
 
-    pub fn run(&mut self) {
+    /// Takes requests off the queue and handles them until the queue
+    /// is closed
+    fn work(&self, queue: &Mutex<mpsc::Receiver<(time::Instant, tiny_http::Request)>>) {
         loop {
-            let req = match self.http.recv() {
-                Ok(req) => req,
-                Err(e) => {
-                    println!("http error: {:?}", e);
-                    continue;
-                }
+            // the lock is released as soon as we've got a request,
+            // so the other workers can wait for the next one
+            let (start_t, req) = match queue.lock().unwrap().recv() {
+                Ok(queued) => queued,
+                Err(mpsc::RecvError) => return,
             };
 
-            self.handle_request(&self.base_url, req);
+            self.activity.lock().unwrap().requests += 1;
+
+            if self.is_shutting_down() {
+                // it hasn't started yet, the client may retry later
+                self.respond_error(
+                    start_t, req,
+                    Error::new(ErrorKind::Unavailable,
+                               "Server is shutting down"));
+            } else {
+                self.handle_request(&self.base_url, start_t, req);
+            }
+
+            let mut activity = self.activity.lock().unwrap();
+            activity.requests -= 1;
+            if activity.requests == 0 {
+                self.idle.notify_all();
+            }
+        }
+    }
+
+    /// Waits up to `config.shutdown_timeout` for the requests being
+    /// handled. If they don't finish in time, removes the payloads that
+    /// are still being received and exits, since the workers can't be
+    /// interrupted.
+    fn wait_for_workers(&self) {
+        let activity = self.activity.lock().unwrap();
+        if activity.requests > 0 {
+            println!("Waiting up to {} s for {} requests to finish",
+                     self.config.shutdown_timeout.as_secs(),
+                     activity.requests);
+        }
+
+        let (mut activity, wait) = self.idle
+            .wait_timeout_while(activity, self.config.shutdown_timeout,
+                                |activity| activity.requests > 0)
+            .unwrap();
+        if !wait.timed_out() {
+            return;
+        }
+
+        for path in activity.incomplete.drain() {
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Removed incomplete {}", path.display()),
+                Err(e) => println!("Could not remove incomplete {}: {}",
+                                   path.display(), e),
+            }
+        }
+        println!("{} requests haven't finished in time, quitting anyway",
+                 activity.requests);
+        process::exit(1);
+    }
+
+    /// Accepts requests and hands them to a pool of `config.workers`
+    /// threads. If all workers are busy and there are already
+    /// `config.queue_size` requests waiting, the request is rejected
+    /// with 503 right away.
+    ///
+    /// Returns once asked to shut down and the requests being handled
+    /// are done, see `handle_signals`.
+    /// Measures the uploads directory every `quota::MEASURE_INTERVAL`
+    /// until shutdown, so files removed by others stop counting
+    fn measure_quota(&self) {
+        let mut measured = time::Instant::now();
+        while !self.is_shutting_down() {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            if measured.elapsed() >= quota::MEASURE_INTERVAL {
+                self.quota.measure();
+                measured = time::Instant::now();
+            }
         }
     }
+
+    pub fn run(&self) {
+        self.clean_staging_dir();
+
+        let (sender, receiver) =
+            mpsc::sync_channel::<(time::Instant, tiny_http::Request)>(
+                self.config.queue_size);
+        let receiver = Mutex::new(receiver);
+
+        thread::scope(|scope| {
+            for _ in 0..self.config.workers {
+                scope.spawn(|| self.work(&receiver));
+            }
+            if self.quota.is_enabled() {
+                scope.spawn(|| self.measure_quota());
+            }
+
+            while !self.is_shutting_down() {
+                let req = match self.http.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                    Ok(Some(req)) => req,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("http error: {:?}", e);
+                        continue;
+                    }
+                };
+
+                match sender.try_send((time::Instant::now(), req)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full((start_t, req))) => {
+                        self.respond_error(
+                            start_t, req,
+                            Error::new(
+                                ErrorKind::Unavailable,
+                                "Too many requests, try again later"));
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        unreachable!("all workers have quit");
+                    }
+                }
+            }
+
+            println!("Shutting down");
+            // let the workers quit once the queue is empty
+            drop(sender);
+            self.wait_for_workers();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use chrono::TimeZone;
+    use std::net::TcpStream;
+    use crate::test_util::{test_config, test_dir};
+
+    fn some_time() -> chrono::DateTime<chrono::Local> {
+        chrono::Local.ymd(2021, 3, 14).and_hms_nano(15, 9, 26, 535897932)
+    }
+
+    #[test]
+    fn test_parse_mangled_file() {
+        let filename = mangle_filename(
+            some_time(), UploadType::File, FileType::Payload, Some("cat.jpg"));
+
+        assert_eq!(
+            parse_mangled_filename(&filename),
+            Some(MangledFilename {
+                timestamp: some_time().naive_local(),
+                name: Some("cat.jpg"),
+                typ: UploadType::File,
+                file_type: FileType::Payload,
+            })
+        )
+    }
+
+    #[test]
+    fn test_parse_mangled_text() {
+        let filename = mangle_filename::<&str>(
+            some_time(), UploadType::Text, FileType::Metadata, None);
+
+        assert_eq!(
+            parse_mangled_filename(&filename),
+            Some(MangledFilename {
+                timestamp: some_time().naive_local(),
+                name: None,
+                typ: UploadType::Text,
+                file_type: FileType::Metadata,
+            })
+        )
+    }
+
+    #[test]
+    fn test_parse_foreign_file() {
+        assert_eq!(parse_mangled_filename("notes.txt"), None);
+        assert_eq!(
+            parse_mangled_filename("2021-03-14--15:09:26.5--x--file.bin--junk"),
+            None);
+        assert_eq!(
+            parse_mangled_filename("yesterday--noon--x--file.bin--payload"),
+            None);
+    }
+
+    fn payloads(dir: &path::Path) -> Vec<path::PathBuf> {
+        fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_str().unwrap().ends_with("--payload"))
+            .collect()
+    }
+
+    #[test]
+    fn test_shutdown_during_upload() {
+        const SIZE: usize = 256 * 1024;
+
+        let dir = test_dir("shutdown");
+        let srv = test_config(&dir, &[]).make_server();
+        srv.handle_signals().unwrap();
+        let port = srv.base_url().port().unwrap();
+        let server = thread::spawn(move || srv.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "PUT /file/slow.bin HTTP/1.1\r\n\
+                        Content-Length: {}\r\n\
+                        Connection: close\r\n\r\n", SIZE).unwrap();
+        stream.write_all(&[b'x'; SIZE / 2]).unwrap();
+
+        // wait until the upload is being received,
+        // it's not in the uploads directory until it's complete
+        let started = time::Instant::now();
+        let staging_dir = dir.join(STAGING_DIR);
+        while fs::read_dir(&staging_dir).map_or(0, |d| d.count()) == 0 {
+            assert!(started.elapsed() < time::Duration::from_secs(5));
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert!(payloads(&dir).is_empty());
+
+        // a second signal doesn't cut the upload short
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        thread::sleep(SHUTDOWN_POLL_INTERVAL * 3);
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        thread::sleep(SHUTDOWN_POLL_INTERVAL * 3);
+        stream.write_all(&[b'x'; SIZE / 2]).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // run returns once the upload is done
+        server.join().unwrap();
+
+        let payloads = payloads(&dir);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(fs::metadata(&payloads[0]).unwrap().len(), SIZE as u64);
+        assert_eq!(fs::read_dir(&staging_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tus_finish_failure() {
+        let dir = test_dir("tus-finish");
+        let srv = test_config(&dir, &["--dedup"]).make_server();
+        // blobs can't be created
+        fs::write(dir.join(BLOBS_DIR), b"").unwrap();
+
+        let upload = srv.tus.create(5, tus::FileInfo::default()).unwrap();
+        let upload = srv.tus.append(&upload.id, 0, &mut &b"hello"[..]).unwrap();
+        assert!(upload.is_complete());
+        let req = tiny_http::TestRequest::new().with_method(tiny_http::Method::Patch);
+        assert!(srv.finish_tus_upload(&upload, &req.into()).is_err());
+
+        // the client can try again
+        assert_eq!(srv.tus.get(&upload.id).unwrap().offset, 5);
+
+        fs::remove_file(dir.join(BLOBS_DIR)).unwrap();
+        srv.finish_tus_upload(&upload, &tiny_http::TestRequest::new().into())
+            .unwrap();
+        assert!(srv.tus.get(&upload.id).is_err());
+        assert_eq!(payloads(&dir).len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_staging() {
+        let dir = test_dir("staging");
+        let srv = test_config(&dir, &[]).make_server();
+
+        let now = some_time();
+        let path = srv.stored_path(
+            now, UploadType::File, FileType::Payload, Some("cat.jpg"));
+        let mut file = srv.create_file(&path).unwrap();
+        file.write_all(b"meow").unwrap();
+        assert!(!path.exists());
+
+        srv.publish(&srv.staging_path(&path), &path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"meow");
+
+        // the name is taken now
+        assert!(srv.create_file(&path).is_err());
+
+        // a file that can't be published doesn't stay staged
+        let staging_path = srv.staging_path(&dir.join("other"));
+        fs::write(&staging_path, b"purr").unwrap();
+        assert!(srv.publish(&staging_path, &path).is_err());
+        assert!(!staging_path.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"meow");
+
+        // leftovers from a crash are removed on startup
+        let leftover = srv.staging_path(&srv.stored_path(
+            now, UploadType::File, FileType::Payload, Some("dog.jpg")));
+        fs::write(&leftover, b"wo").unwrap();
+        srv.clean_staging_dir();
+        assert!(!leftover.exists());
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_quota() {
+        let dir = test_dir("quota");
+        fs::write(dir.join("notes.txt"), b"1234").unwrap();
+        let srv = test_config(&dir, &["--quota", "10"]).make_server();
+        assert_eq!(srv.remaining_quota().unwrap(), Some(6));
+
+        srv.write_text(&PayloadName {
+            now: some_time(),
+            typ: UploadType::Text,
+            name: None,
+            client_ip: "192.0.2.1".parse().unwrap(),
+        }, "hello", &srv.quota.reserve()).unwrap();
+        assert_eq!(srv.remaining_quota().unwrap(), Some(1));
+        assert_eq!(srv.upload_limit(Some(100)).unwrap(), Some(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_name_template() {
+        let dir = test_dir("name-template");
+        let srv = test_config(&dir, &[
+            "--name-template", "{orig}_{type}.{ext}",
+            "--save-meta",
+        ]).make_server();
+
+        let name = PayloadName {
+            now: some_time(),
+            typ: UploadType::File,
+            name: Some("cat.jpg"),
+            client_ip: "192.0.2.1".parse().unwrap(),
+        };
+        let filename = srv.payload_filename(&name, "abc");
+        assert_eq!(filename, "cat_file.jpg");
+        assert_eq!(srv.metadata_filename(&filename), "cat_file.jpg.metadata");
+
+        // the same name three times gets numbered
+        for expected in ["cat_file.jpg", "cat_file-1.jpg", "cat_file-2.jpg"] {
+            let staging_path = srv.staging_path(&dir.join("staged"));
+            fs::create_dir_all(staging_path.parent().unwrap()).unwrap();
+            fs::write(&staging_path, b"meow").unwrap();
+            assert_eq!(srv.publish_payload(&staging_path, &name,
+                                           filename.clone()).unwrap(),
+                       expected);
+            assert!(!staging_path.exists());
+        }
+
+        // metadata, old names and anything else the operator has put
+        // there aren't payloads
+        fs::write(dir.join("cat_file.jpg.metadata"), b"headers").unwrap();
+        fs::write(dir.join("2021-03-14--15-09-26--file--metadata"), b"").unwrap();
+        fs::write(dir.join("notes.txt"), b"private").unwrap();
+
+        assert_eq!(srv.templated_payload("cat_file-1.jpg"),
+                   Some((some_time().naive_local(), UploadType::File)));
+        assert!(srv.templated_payload("cat_file.jpg.metadata").is_none());
+        assert!(srv.templated_payload("notes.txt").is_none());
+        assert!(srv.templated_payload(".partial").is_none());
+        assert_eq!(srv.stored_files().unwrap().len(), 3);
+        assert!(srv.handle_file_download("notes.txt").is_err());
+        assert!(srv.handle_file_download("cat_file-2.jpg").is_ok());
+
+        // texts are listed and served as texts
+        let text = PayloadName { typ: UploadType::Text, name: None, ..name };
+        let staging_path = srv.staging_path(&dir.join("staged"));
+        fs::write(&staging_path, b"purr").unwrap();
+        let text_filename = srv.publish_payload(
+            &staging_path, &text, srv.payload_filename(&text, "abc")).unwrap();
+        let files = srv.stored_files().unwrap();
+        let stored = files.iter()
+            .find(|file| file.filename == text_filename)
+            .unwrap();
+        assert_eq!(stored.typ, UploadType::Text);
+        assert_eq!(stored.timestamp, some_time().naive_local());
+        let resp = srv.handle_file_download(&text_filename).unwrap();
+        assert!(resp.headers().iter().any(|h| {
+            h.field.equiv("Content-Type") &&
+                h.value.as_str().starts_with("text/plain")
+        }));
+        fs::remove_file(dir.join(&text_filename)).unwrap();
+
+        // a leftover metadata file takes the name too
+        fs::write(dir.join("cat_file-3.jpg.metadata"), b"headers").unwrap();
+        let staging_path = srv.staging_path(&dir.join("staged"));
+        fs::write(&staging_path, b"meow").unwrap();
+        assert_eq!(srv.publish_payload(&staging_path, &name, filename)
+                   .unwrap(), "cat_file-4.jpg");
+
+        // and clients can't take the metadata names
+        let name = PayloadName { name: Some("cat.metadata"), ..name };
+        assert_eq!(srv.payload_filename(&name, "abc"), "cat_file_metadata");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_without_credentials() {
+        let req = RequestInfo {
+            method: tiny_http::Method::Post,
+            http_version: tiny_http::HTTPVersion(1, 1),
+            remote_addr: "192.0.2.1:1234".parse().unwrap(),
+            headers: ["Authorization: Bearer secret", "Cookie: session=secret",
+                      "proxy-authorization: Basic c2VjcmV0", "User-Agent: curl"]
+                .iter()
+                .map(|h| h.parse().unwrap())
+                .collect(),
+        };
+        let payload = StoredPayload {
+            now: some_time(),
+            typ: UploadType::File,
+            stored_filename: "cat.jpg",
+            original_name: Some("cat.jpg"),
+            content_type: None,
+            size: 4,
+            sha256: "abc".to_string(),
+        };
+
+        let json = payload.to_json(&req);
+        let headers = json["headers"].as_object().unwrap();
+        assert_eq!(headers.keys().collect::<Vec<_>>(), ["User-Agent"]);
+        assert!(!json.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_route_name() {
+        let base_url = Url::parse("http://localhost:2022").unwrap();
+        assert_eq!(route_name(&base_url, "/"), "home");
+        assert_eq!(route_name(&base_url, "/file/cat.jpg"), "file");
+        assert_eq!(route_name(&base_url, "/api/v1/text"), "text");
+        assert_eq!(route_name(&base_url, "/api/v1/"), "other");
+        assert_eq!(route_name(&base_url, "/assets/../tus/abc"), "tus");
+        assert_eq!(route_name(&base_url, "/nope"), "other");
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(content_disposition("attachment", "cat.jpg"),
+                   r#"attachment; filename="cat.jpg""#);
+        assert_eq!(content_disposition("inline", "отчёт 1.pdf"),
+                   "inline; filename=\"_____ 1.pdf\"; filename*=UTF-8''\
+                    %D0%BE%D1%82%D1%87%D1%91%D1%82%201.pdf");
+    }
+
+    #[test]
+    fn test_content_disposition_filename() {
+        assert_eq!(
+            content_disposition_filename(r#"attachment; filename="cat.jpg""#),
+            Some("cat.jpg".to_string()));
+        assert_eq!(
+            content_disposition_filename("attachment; filename=cat.jpg"),
+            Some("cat.jpg".to_string()));
+        assert_eq!(
+            content_disposition_filename(
+                "attachment; filename=\"cat.jpg\"; \
+                 filename*=UTF-8''%D0%BA%D0%BE%D1%82.jpg"),
+            Some("кот.jpg".to_string()));
+        // a broken filename* doesn't hide the plain one
+        assert_eq!(
+            content_disposition_filename(
+                "attachment; filename*=UTF-8''%FF.jpg; filename=\"cat.jpg\""),
+            Some("cat.jpg".to_string()));
+        assert_eq!(
+            content_disposition_filename(
+                "attachment; filename*=garbage; filename=\"cat.jpg\""),
+            Some("cat.jpg".to_string()));
+        assert_eq!(
+            content_disposition_filename(r#"attachment; filename="""#), None);
+        assert_eq!(content_disposition_filename("inline"), None);
+    }
 }
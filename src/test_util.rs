@@ -0,0 +1,26 @@
+//! Fixtures shared by the tests of all modules
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// An empty directory for the test, unique to the test process
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "upload-server-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Config for a server on a random port storing into `uploads_dir`,
+/// `args` are added to the command line. Servers borrow their config,
+/// so it lives until the tests are done.
+pub fn test_config(uploads_dir: &Path, args: &[&str]) -> &'static Config {
+    let config = Config::parse_args(&mut [
+        "--listen", "127.0.0.1:0",
+        "--uploads-dir", uploads_dir.to_str().unwrap(),
+    ].iter().chain(args).map(|s| s.to_string())).unwrap();
+    Box::leak(Box::new(config))
+}
@@ -0,0 +1,109 @@
+//! ASCII spelling of Cyrillic, Greek and Latin letters with diacritics,
+//! for `--filename-mode transliterate`
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Letters that don't decompose into an ASCII letter and marks,
+/// in lower case
+fn lowercase_to_ascii(c: char) -> Option<&'static str> {
+    Some(match c {
+        // Russian
+        'а' => "a", 'б' => "b", 'в' => "v", 'г' => "g", 'д' => "d",
+        'е' => "e", 'ё' => "yo", 'ж' => "zh", 'з' => "z", 'и' => "i",
+        'й' => "y", 'к' => "k", 'л' => "l", 'м' => "m", 'н' => "n",
+        'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+        'у' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch",
+        'ш' => "sh", 'щ' => "shch", 'ъ' => "", 'ы' => "y", 'ь' => "",
+        'э' => "e", 'ю' => "yu", 'я' => "ya",
+
+        // Ukrainian, Belarusian and South Slavic
+        'є' => "ye", 'і' => "i", 'ї' => "yi", 'ґ' => "g", 'ў' => "u",
+        'ђ' => "dj", 'ј' => "j", 'љ' => "lj", 'њ' => "nj", 'ћ' => "c",
+        'џ' => "dz", 'ѓ' => "gj", 'ќ' => "kj", 'ѕ' => "dz",
+
+        // Greek, accents are dropped by the decomposition
+        'α' => "a", 'β' => "v", 'γ' => "g", 'δ' => "d", 'ε' => "e",
+        'ζ' => "z", 'η' => "i", 'θ' => "th", 'ι' => "i", 'κ' => "k",
+        'λ' => "l", 'μ' => "m", 'ν' => "n", 'ξ' => "x", 'ο' => "o",
+        'π' => "p", 'ρ' => "r", 'σ' => "s", 'ς' => "s", 'τ' => "t",
+        'υ' => "y", 'φ' => "f", 'χ' => "ch", 'ψ' => "ps", 'ω' => "o",
+
+        // Latin
+        'ß' => "ss", 'æ' => "ae", 'œ' => "oe", 'ø' => "o", 'ł' => "l",
+        'đ' => "d", 'ð' => "d", 'þ' => "th", 'ı' => "i", 'ŋ' => "ng",
+
+        _ => return None,
+    })
+}
+
+/// Keeps the case of the first letter, "Ж" becomes "Zh", or of all of
+/// them with `all_caps`, "ZH"
+fn to_ascii(c: char, all_caps: bool) -> Option<String> {
+    let lower = c.to_lowercase().next()?;
+    let ascii = lowercase_to_ascii(lower)?;
+    if lower == c {
+        return Some(ascii.to_string());
+    }
+    if all_caps {
+        return Some(ascii.to_ascii_uppercase());
+    }
+
+    let mut chars = ascii.chars();
+    Some(match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    })
+}
+
+/// Whether the letter at `i` is in an upper case word, going by the next
+/// letter or by the previous one at the end of the word
+fn in_all_caps(chars: &[char], i: usize) -> bool {
+    match chars.get(i + 1) {
+        Some(next) if next.is_alphabetic() => next.is_uppercase(),
+        _ => i > 0 && chars[i - 1].is_uppercase(),
+    }
+}
+
+/// Replaces the letters we know with ASCII and drops diacritics,
+/// other characters are left as they are
+pub fn transliterate(s: &str) -> String {
+    let chars: Vec<char> = s.nfc().collect();
+    let mut out = String::with_capacity(s.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let all_caps = in_all_caps(&chars, i);
+
+        // some letters like "й" and "ё" decompose, so look them up first
+        if let Some(ascii) = to_ascii(c, all_caps) {
+            out.push_str(&ascii);
+            continue;
+        }
+
+        for c in std::iter::once(c).nfd().filter(|&c| !is_combining_mark(c)) {
+            match to_ascii(c, all_caps) {
+                Some(ascii) => out.push_str(&ascii),
+                None => out.push(c),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_transliterate() {
+        assert_eq!(transliterate("Отчёт за май"), "Otchyot za may");
+        assert_eq!(transliterate("ЖУРНАЛ"), "ZHURNAL");
+        assert_eq!(transliterate("Журнал ЁЖ Ж"), "Zhurnal YOZH Zh");
+        assert_eq!(transliterate("ЩИ.txt"), "SHCHI.txt");
+        assert_eq!(transliterate("Їжак і ґанок"), "Yizhak i ganok");
+        assert_eq!(transliterate("Ελληνικά έγγραφα"), "Ellinika eggrafa");
+        assert_eq!(transliterate("Crème brûlée à Łódź"), "Creme brulee a Lodz");
+        assert_eq!(transliterate("Straße Ærø"), "Strasse Aero");
+        assert_eq!(transliterate("報告🐧"), "報告🐧");
+    }
+}
@@ -0,0 +1,285 @@
+//! Staging area for resumable uploads using the tus 1.0 protocol,
+//! see https://tus.io/protocols/resumable-upload
+//!
+//! Every upload is a pair of files in the staging directory:
+//! `{id}` with the data received so far and `{id}.info` with the
+//! declared length and what the client has told about the file.
+//! Keeping the state on disk lets clients resume after the server
+//! is restarted.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use base64::Engine;
+
+use crate::error::{Error, ErrorKind};
+use crate::hash;
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+
+/// Name of the staging directory inside the uploads directory
+const STAGING_DIR: &str = ".tus";
+
+/// What the client has told about the file in Upload-Metadata
+#[derive(Default)]
+pub struct FileInfo {
+    /// Sanitized name for storing the file
+    pub filename: Option<String>,
+    pub original_filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+pub struct Upload {
+    pub id: String,
+    pub length: u64,
+    pub offset: u64,
+    pub info: FileInfo,
+}
+
+impl Upload {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+}
+
+pub struct Staging {
+    dir: PathBuf,
+
+    /// Uploads that are being appended to right now
+    busy: Mutex<HashSet<String>>,
+}
+
+/// Releases the upload when the PATCH is done with it
+struct BusyGuard<'a> {
+    staging: &'a Staging,
+    id: String,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.staging.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Parses the Upload-Metadata header: comma-separated pairs of a key
+/// and a base64-encoded value, the value may be omitted
+pub fn parse_metadata(header: &str) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let mut metadata = HashMap::new();
+
+    for pair in header.split(',') {
+        let pair = pair.trim();
+        if pair.is_empty() {
+            continue;
+        }
+
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().unwrap_or("");
+        let value = match parts.next() {
+            Some(value) => base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .map_err(|e| Error::new(
+                    ErrorKind::UserError,
+                    format!("Invalid Upload-Metadata value for {}: {}",
+                            key, e)))?,
+            None => Vec::new(),
+        };
+
+        metadata.insert(key.to_string(), value);
+    }
+
+    Ok(metadata)
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+fn not_found(id: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("No upload \"{}\"", id))
+}
+
+impl Staging {
+    pub fn new<P: AsRef<Path>>(uploads_dir: P) -> Self {
+        Staging {
+            dir: uploads_dir.as_ref().join(STAGING_DIR),
+            busy: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.info", id))
+    }
+
+    /// Starts a new upload of `length` bytes
+    pub fn create(&self, length: u64, info: FileInfo) -> io::Result<Upload> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut id_bytes = [0u8; 16];
+        openssl::rand::rand_bytes(&mut id_bytes)?;
+        let id = hash::to_hex(&id_bytes);
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.data_path(&id))?;
+
+        let json = serde_json::json!({
+            "length": length,
+            "filename": info.filename,
+            "original_filename": info.original_filename,
+            "content_type": info.content_type,
+        });
+        fs::write(self.info_path(&id), json.to_string())?;
+
+        Ok(Upload { id, length, offset: 0, info })
+    }
+
+    /// Looks up an upload, Err with ErrorKind::NotFound if there's none
+    pub fn get(&self, id: &str) -> Result<Upload, Error> {
+        if !is_valid_id(id) {
+            return Err(not_found(id));
+        }
+
+        let info = match fs::read_to_string(self.info_path(id)) {
+            Ok(info) => info,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(not_found(id));
+            }
+            Err(e) => return Err(Error::from_io_error(e, "read upload info")),
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&info)
+            .map_err(|e| Error::new(
+                ErrorKind::ServerError,
+                format!("Upload \"{}\" has invalid info: {}", id, e)))?;
+        let string = |key: &str| json[key].as_str().map(str::to_string);
+
+        let length = json["length"].as_u64().ok_or_else(|| Error::new(
+            ErrorKind::ServerError,
+            format!("Upload \"{}\" has no length", id)))?;
+        let info = FileInfo {
+            filename: string("filename"),
+            original_filename: string("original_filename"),
+            content_type: string("content_type"),
+        };
+
+        let offset = fs::metadata(self.data_path(id))
+            .map_err(|e| Error::from_io_error(e, "read upload data"))?
+            .len();
+
+        Ok(Upload { id: id.to_string(), length, offset, info })
+    }
+
+    /// Appends the data at `offset`, which must be where the previous
+    /// PATCH has ended. Whatever is received is kept even if
+    /// the connection breaks, so the client can resume from there.
+    pub fn append(&self, id: &str, offset: u64, data: &mut dyn Read)
+                  -> Result<Upload, Error> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return Err(Error::new(
+                ErrorKind::Conflict,
+                format!("Upload \"{}\" is being written to already", id)));
+        }
+        let _guard = BusyGuard { staging: self, id: id.to_string() };
+
+        // only now the offset can't change under us
+        let upload = self.get(id)?;
+        if offset != upload.offset {
+            return Err(Error::new(
+                ErrorKind::Conflict,
+                format!("Upload-Offset is {}, expected {}",
+                        offset, upload.offset)));
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(self.data_path(id))
+            .map_err(|e| Error::from_io_error(e, "open upload data"))?;
+
+        let remaining = upload.length - upload.offset;
+        let copied = io::copy(&mut data.take(remaining), &mut file);
+        file.flush()
+            .map_err(|e| Error::from_io_error(e, "write upload data"))?;
+        copied.map_err(|e| Error::from_io_error(e, "receive upload data"))?;
+
+        self.get(id)
+    }
+
+    /// Forgets the upload and deletes whatever was received
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        self.get(id)?;
+
+        fs::remove_file(self.info_path(id))
+            .map_err(|e| Error::from_io_error(e, "remove upload info"))?;
+        match fs::remove_file(self.data_path(id)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::from_io_error(e, "remove upload data")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata = parse_metadata(
+            "filename d29ybGQucGRm,is_confidential, type dGV4dA==").unwrap();
+
+        assert_eq!(metadata["filename"], b"world.pdf");
+        assert_eq!(metadata["is_confidential"], b"");
+        assert_eq!(metadata["type"], b"text");
+        assert!(parse_metadata("filename !!!").is_err());
+    }
+
+    #[test]
+    fn test_resume_upload() {
+        let dir = test_dir("tus");
+        let staging = Staging::new(&dir);
+
+        let upload = staging.create(11, FileInfo {
+            filename: Some("hello.txt".to_string()),
+            ..Default::default()
+        }).unwrap();
+        let id = upload.id;
+
+        let upload = staging.append(&id, 0, &mut &b"hello"[..]).unwrap();
+        assert_eq!(upload.offset, 5);
+        assert!(!upload.is_complete());
+
+        // resuming from the wrong place is refused
+        assert!(staging.append(&id, 3, &mut &b"lo world"[..]).is_err());
+
+        // anything past the declared length is ignored
+        let upload = staging.append(&id, 5, &mut &b" world!!!"[..]).unwrap();
+        assert!(upload.is_complete());
+        assert_eq!(upload.info.filename.as_deref(), Some("hello.txt"));
+        assert_eq!(fs::read(staging.data_path(&id)).unwrap(), b"hello world");
+
+        staging.remove(&id).unwrap();
+        assert!(staging.get(&id).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_id() {
+        let dir = test_dir("tus-id");
+        let staging = Staging::new(&dir);
+        assert!(staging.get("../../etc/passwd").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
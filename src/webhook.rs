@@ -0,0 +1,237 @@
+//! `--webhook` notifications: a JSON event is POSTed to the URL after
+//! every stored payload. Delivery happens on a background thread and is
+//! retried with exponential backoff, events are sent one by one in order.
+//! If the endpoint is down for long, events that don't fit in the queue
+//! are dropped.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use openssl::ssl::{SslConnector, SslMethod};
+use url::{Host, Url};
+
+/// How many times we try to deliver an event
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the second attempt, doubled after every next one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// For connecting and for every read and write
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many events may wait for delivery
+const QUEUE_SIZE: usize = 1000;
+
+pub struct Webhook {
+    sender: mpsc::SyncSender<serde_json::Value>,
+}
+
+impl Webhook {
+    pub fn start(url: Url) -> Self {
+        Self::with_backoff(url, INITIAL_BACKOFF)
+    }
+
+    fn with_backoff(url: Url, backoff: Duration) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<serde_json::Value>(QUEUE_SIZE);
+
+        thread::spawn(move || {
+            for event in receiver {
+                let body = event.to_string();
+                if let Err(e) = deliver(&url, body.as_bytes(), backoff) {
+                    println!("Webhook gave up on {}: {}", event, e);
+                }
+            }
+        });
+
+        Webhook { sender }
+    }
+
+    /// Queues the event for delivery, failures are only logged
+    pub fn notify(&self, event: serde_json::Value) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                println!("Webhook queue is full, dropping {}", event);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                println!("Webhook thread has quit");
+            }
+        }
+    }
+}
+
+fn deliver(url: &Url, body: &[u8], backoff: Duration) -> Result<(), String> {
+    let mut wait = backoff;
+    let mut attempt = 1;
+    loop {
+        match post(url, body) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+            Err(e) => {
+                println!("Webhook attempt {} failed: {}, retrying in {:?}",
+                         attempt, e, wait);
+                thread::sleep(wait);
+                wait *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Connects to the first address of the URL's host that accepts
+fn connect(url: &Url) -> Result<TcpStream, String> {
+    let host = url.host_str().ok_or("no host in the URL")?;
+    let addrs = url.to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?;
+
+    let mut error = format!("no addresses for {}", host);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = format!("cannot connect to {}: {}", addr, e),
+        }
+    }
+    Err(error)
+}
+
+/// POSTs the JSON body, Ok if the response status is 2xx
+fn post(url: &Url, body: &[u8]) -> Result<(), String> {
+    // with brackets around IPv6 addresses, like the Host header wants
+    let host = url.host_str().ok_or("no host in the URL")?;
+
+    let stream = connect(url)?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: upload-server\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        path, host_header, body.len());
+    if !url.username().is_empty() {
+        let credentials = format!(
+            "{}:{}", url.username(), url.password().unwrap_or(""));
+        request.push_str(&format!(
+            "Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials)));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+
+    match url.scheme() {
+        "http" => exchange(stream, &request),
+        "https" => {
+            // the certificate has IPv6 addresses without brackets
+            let name = match url.host() {
+                Some(Host::Ipv6(addr)) => addr.to_string(),
+                _ => host.to_string(),
+            };
+            let connector = SslConnector::builder(SslMethod::tls())
+                .map_err(|e| e.to_string())?
+                .build();
+            let stream = connector.connect(&name, stream)
+                .map_err(|e| format!("TLS error: {}", e))?;
+            exchange(stream, &request)
+        }
+        scheme => Err(format!("unsupported scheme {}", scheme)),
+    }
+}
+
+/// Sends the request and checks the response status
+fn exchange<S: Read + Write>(mut stream: S, request: &[u8])
+                             -> Result<(), String> {
+    stream.write_all(request)
+        .and_then(|()| stream.flush())
+        .map_err(|e| format!("cannot send: {}", e))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)
+        .map_err(|e| format!("cannot read the response: {}", e))?;
+
+    let status = status_line.split_whitespace().nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| format!("invalid response \"{}\"", status_line.trim()))?;
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("got {}", status_line.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://user:secret@{}/hook?x=1", server.server_addr())).unwrap();
+
+        let webhook = Webhook::with_backoff(url, Duration::from_millis(10));
+        webhook.notify(serde_json::json!({ "size": 5 }));
+
+        // fail the first attempt
+        let req = server.recv().unwrap();
+        req.respond(tiny_http::Response::empty(503)).unwrap();
+
+        let mut req = server.recv().unwrap();
+        assert_eq!(req.method(), &tiny_http::Method::Post);
+        assert_eq!(req.url(), "/hook?x=1");
+        let authorization = req.headers().iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.to_string());
+        assert_eq!(authorization.as_deref(), Some("Basic dXNlcjpzZWNyZXQ="));
+
+        let mut body = String::new();
+        req.as_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, r#"{"size":5}"#);
+        req.respond(tiny_http::Response::empty(204)).unwrap();
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let server = tiny_http::Server::http("[::1]:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/hook", server.server_addr())).unwrap();
+
+        let client = thread::spawn(move || post(&url, b"{}"));
+        let req = server.recv().unwrap();
+        let host = req.headers().iter()
+            .find(|h| h.field.equiv("Host"))
+            .map(|h| h.value.to_string());
+        assert_eq!(host, Some(server.server_addr().to_string()));
+        req.respond(tiny_http::Response::empty(204)).unwrap();
+
+        assert_eq!(client.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn test_give_up() {
+        // nothing listens there once it's dropped
+        let addr = tiny_http::Server::http("127.0.0.1:0").unwrap()
+            .server_addr();
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+
+        assert!(deliver(&url, b"{}", Duration::from_millis(1)).is_err());
+    }
+}